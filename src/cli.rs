@@ -1,5 +1,11 @@
+use crate::smtp::auth::AuthMode;
+use crate::smtp::chaos::{parse_duration, Chaos};
+use crate::smtp::{Extension, TlsMode};
 use clap::Parser;
 use colored::Colorize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(name = "mail-sink", author, version, about, disable_help_flag = true)]
@@ -12,21 +18,149 @@ pub struct Args {
         long,
         default_value = "2525",
         value_name = "SMTP PORTS",
-        help = "Example: `25,587,465`"
+        help = "Example: `25,587,465:tls,2526:drop=0.1`, `:tls` ports use implicit TLS, `:key=value` options inject faults"
     )]
     pub smtp_port: String,
 
 
+    #[arg(
+        long,
+        default_value = "localhost",
+        help = "The name used in SMTP replies and in the generated certificate"
+    )]
+    pub hostname: String,
+
+    #[arg(
+        long,
+        default_value = "cert.pem",
+        value_name = "PATH",
+        help = "The TLS certificate (chain), reloaded on change or SIGHUP"
+    )]
+    pub tls_cert: PathBuf,
+
+    #[arg(
+        long,
+        default_value = "key.pem",
+        value_name = "PATH",
+        help = "The TLS private key (RSA, SEC1 or PKCS#8), reloaded on change or SIGHUP"
+    )]
+    pub tls_key: PathBuf,
+
+    #[arg(long, help = "Disable TLS, STARTTLS won't be advertised")]
+    pub no_tls: bool,
+
+    #[arg(
+        long,
+        help = "Write the generated self-signed certificate to --tls-cert / --tls-key"
+    )]
+    pub write_cert: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "tls",
+        help = "When SMTP AUTH is advertised: `off`, `tls` (after STARTTLS or on `:tls` ports) or `always`"
+    )]
+    pub auth: AuthMode,
+
+    #[arg(
+        long,
+        value_name = "USER:PASSWORD",
+        help = "Allowed AUTH credentials, can be repeated. Any credentials are accepted when unset"
+    )]
+    pub auth_user: Vec<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        ignore_case = true,
+        default_value = "SIZE,8BITMIME,PIPELINING,SMTPUTF8,ENHANCEDSTATUSCODES,CHUNKING,BINARYMIME",
+        help = "The ESMTP extensions advertised in the EHLO reply"
+    )]
+    pub extensions: Vec<Extension>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        value_name = "BYTES",
+        help = "The maximum message size, bigger mails are refused with 552. 0 for no limit"
+    )]
+    pub max_size: usize,
+
+    #[arg(
+        long,
+        value_name = "RULE",
+        help = "Scripted reply, like `*@fail.test -> 550 5.1.1` or `mail:greylist@* -> 451 on first attempt`. Can be repeated"
+    )]
+    pub rule: Vec<String>,
+
+    #[arg(long, value_name = "PATH", help = "File with one rule per line, `#` starts a comment")]
+    pub rules_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DELAY",
+        value_parser = parse_duration,
+        help = "Refuse the first attempt of each (client IP, sender, recipient) with 451 and accept retries after DELAY, like `5m`"
+    )]
+    pub greylist: Option<Duration>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Pattern of the codes returned by /mails/<id>/codes, the first capture group is the code. Can be repeated, replaces the defaults"
+    )]
+    pub code_regex: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "WEBHOOK",
+        help = "URL to POST every stored mail to, like `https://ci.test/hook` or `to:*@ci.test from:*@github.com -> https://ci.test/hook`. Can be repeated"
+    )]
+    pub webhook: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "SECRET",
+        help = "Sign the webhook payloads with HMAC-SHA256 in the X-Mail-Sink-Signature header"
+    )]
+    pub webhook_secret: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "5",
+        value_name = "COUNT",
+        help = "How many times a failed webhook is retried, waiting 1s, 2s, 4s... in between"
+    )]
+    pub webhook_retries: u32,
+
     #[arg(long, default_value = "8080", value_name = "HTTP PORT")]
     pub http_ports: u16,
 
     #[arg(
         short,
         long,
-        default_value = "prouteur",
-        help = "The key to access the API"
+        help = "An admin key to access the API. Defaults to `prouteur` when no --api-key is given"
     )]
-    pub key: String,
+    pub key: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "NAME:KEY:SCOPES[:DOMAINS[:TENANT]]",
+        help = "Named API key, like `ci:s3cret:read,delete:ci.test`. Scopes are `read`, `delete` and `admin`, domains limit the key to the mails delivered to them, a tenant to the mails of the tenant. Can be repeated"
+    )]
+    pub api_key: Vec<String>,
+
+    #[arg(long, value_name = "PATH", help = "File with one API key per line, `#` starts a comment")]
+    pub api_keys_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "NAME:PATTERNS",
+        help = "Tenant owning the mails delivered to the patterns, like `ci:ci.test,ci-*@sink.test`. Patterns with an `@` match the whole address, others the domain. Mails get every matching tenant. Can be repeated"
+    )]
+    pub tenant: Vec<String>,
 
     #[arg(
         short,
@@ -37,6 +171,48 @@ pub struct Args {
     pub lifetime: Option<u16>,
 }
 
+impl Args {
+    pub fn smtp_listeners(&self) -> Result<Vec<SmtpListener>, String> {
+        self.smtp_port.split(',').map(SmtpListener::from_str).collect()
+    }
+}
+
+/// A single entry of `--smtp-port`, like `2525`, `465:tls` or
+/// `2526:greeting-delay=5s:drop=0.1`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SmtpListener {
+    pub port: u16,
+    pub tls_mode: TlsMode,
+    pub chaos: Chaos,
+}
+
+impl FromStr for SmtpListener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut parts = s.split(':');
+        let port = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<u16>()
+            .map_err(|_| format!("Wrong SMTP port: `{}`", s))?;
+
+        let mut tls_mode = TlsMode::StartTls;
+        let mut chaos = Chaos::default();
+        for option in parts {
+            match option.split_once('=') {
+                None if option.eq_ignore_ascii_case("starttls") => tls_mode = TlsMode::StartTls,
+                None if option.eq_ignore_ascii_case("tls") => tls_mode = TlsMode::Implicit,
+                Some((key, value)) if chaos.set(key, value)? => {}
+                _ => return Err(format!("Unknown SMTP port option `{}` in `{}`", option, s)),
+            }
+        }
+
+        Ok(Self { port, tls_mode, chaos })
+    }
+}
+
 pub static INTRO: &str = "
 Mail Sink is a simple mail server that accepts any incomingemail and stores it in a database. It
 provides an HTTP API to retrieve and delete the stored emails. It can be used for testing email
@@ -51,7 +227,7 @@ running on a higher port number.
 
 pub fn print_api_usage() {
     println!("{}", "API access:".bold());
-    println!("The HTTP API is accessible by adding ?k=your_key to the URL.");
+    println!("The HTTP API is accessible with an `Authorization: Bearer your_key` header, or by adding ?k=your_key to the URL.");
     println!();
     println!(
         "- {} {}                          Retrieve all stored emails (JSON format)",
@@ -67,6 +243,36 @@ pub fn print_api_usage() {
         "GET".blue(),
         "/mails/<email_id>".bold()
     );
+    println!(
+        "- {} {}           Retrieve the raw message of an email",
+        "GET".blue(),
+        "/mails/<email_id>/raw".bold()
+    );
+    println!(
+        "- {} {}   List the attachments of an email (JSON format)",
+        "GET".blue(),
+        "/mails/<email_id>/attachments".bold()
+    );
+    println!(
+        "- {} {}   Retrieve the content of an attachment",
+        "GET".blue(),
+        "/mails/<email_id>/attachments/<n>".bold()
+    );
+    println!(
+        "- {} {}   Retrieve an inline part by Content-ID",
+        "GET".blue(),
+        "/mails/<email_id>/cid/<content_id>".bold()
+    );
+    println!(
+        "- {} {}         Retrieve the links of an email (JSON format)",
+        "GET".blue(),
+        "/mails/<email_id>/links".bold()
+    );
+    println!(
+        "- {} {}         Retrieve the likely verification codes of an email (JSON format)",
+        "GET".blue(),
+        "/mails/<email_id>/codes".bold()
+    );
     println!(
         "- {} {}       Retrieve all emails to (JSON format)",
         "GET".blue(),
@@ -76,6 +282,15 @@ pub fn print_api_usage() {
         "  • {}: ?limit and ?offset for pagination",
         "Parameters".bright_black()
     );
+    println!(
+        "- {} {}  Wait for an email to (JSON format)",
+        "GET".blue(),
+        "/mails/to/<email_address>/wait".bold()
+    );
+    println!(
+        "  • {}: ?timeout (default 30s) and ?after=<email_id>",
+        "Parameters".bright_black()
+    );
     println!(
         "- {} {}     Retrieve all emails from (JSON format)",
         "GET".blue(),
@@ -85,6 +300,20 @@ pub fn print_api_usage() {
         "  • {}: ?limit and ?offset for pagination",
         "Parameters".bright_black()
     );
+    println!(
+        "- {} {}                         Stream the emails as they are stored (Server-Sent Events)",
+        "GET".blue(),
+        "/events".bold()
+    );
+    println!(
+        "- {} {}                      Stream the emails as they are stored (WebSocket)",
+        "GET".blue(),
+        "/events/ws".bold()
+    );
+    println!(
+        "  • {}: ?to and ?from to filter the emails",
+        "Parameters".bright_black()
+    );
     println!(
         "- {} {}            Delete a specific email",
         "DELETE".red(),
@@ -106,4 +335,18 @@ pub fn print_api_usage() {
         "DELETE".red(),
         "/mails/from/<email_address>".bold()
     );
+    println!(
+        "- {} {}                     Retrieve the commands refused by rules (JSON format)",
+        "GET".blue(),
+        "/rejections".bold()
+    );
+    println!(
+        "  • {}: ?limit and ?offset for pagination",
+        "Parameters".bright_black()
+    );
+    println!(
+        "- {} {}                  Delete all logged rejections",
+        "DELETE".red(),
+        "/rejections".bold()
+    );
 }
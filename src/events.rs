@@ -0,0 +1,23 @@
+use crate::smtp::mail::Mail;
+use lazy_static::lazy_static;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Mails stored while a subscriber lags behind by more than this are skipped
+/// for it, it gets a `Lagged` error instead.
+const CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref STORED_MAILS: broadcast::Sender<Arc<Mail>> = broadcast::channel(CAPACITY).0;
+}
+
+/// Notifies the subscribers that `mail` was stored.
+pub fn publish(mail: Mail) {
+    // no subscriber is fine
+    let _ = STORED_MAILS.send(Arc::new(mail));
+}
+
+/// Receives every mail stored from now on.
+pub fn subscribe() -> broadcast::Receiver<Arc<Mail>> {
+    STORED_MAILS.subscribe()
+}
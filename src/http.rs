@@ -1,226 +1,324 @@
+pub(crate) mod auth;
+pub(crate) mod protocol;
+
 use psutil::process::Process;
 use serde_json::{json, Value};
 use sled::Db;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use sysinfo::{Disks, System};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{BufReader, BufWriter};
 use tokio::net::TcpStream;
-
-use tokio::sync::{Mutex as AsyncMutex, Mutex};
-
-use crate::smtp::mail::Mail;
-use url::form_urlencoded;
-use url::Url;
-
-#[derive(Debug, PartialEq, Eq, Hash)]
-enum Method {
-    GET,
-    POST,
-    PUT,
-    DELETE,
-}
-
-impl Method {
-    fn from_str(method: &str) -> Option<Method> {
-        match method.to_uppercase().as_str() {
-            "GET" => Some(Method::GET),
-            "POST" => Some(Method::POST),
-            "PUT" => Some(Method::PUT),
-            "DELETE" => Some(Method::DELETE),
-            _ => None,
-        }
-    }
-}
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+use crate::events;
+use crate::smtp::chaos::parse_duration;
+use crate::smtp::extract::{extract_codes, extract_links};
+use crate::smtp::mail::{read_stored, AddressField, Mail};
+use crate::smtp::mime::Attachment;
+use crate::smtp::rules::{Rejection, REJECTIONS_TREE};
+use crate::tenant::Tenants;
+use crate::websocket;
+use auth::{ApiKey, ApiKeys, Scope};
+use protocol::{Framing, Method, Next, ReadError, Request, Response};
+
+// interval of the comments sent on idle event streams
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+// how long an idle keep-alive connection is kept open
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// the longest `?timeout=` of the wait route
+const MAX_WAIT: Duration = Duration::from_secs(10 * 60);
+
+// a route is the method, the path, the scope the key needs and the handler
+type Route = (Method, String, Scope, Handler);
 
 // Define a type alias for the handler function
 type Handler = Box<
     dyn Fn(
             Request,
-            Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+            Arc<ApiKey>,
             Arc<Mutex<Db>>,
-        )
-            -> Pin<Box<dyn Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send>>
+        ) -> Pin<Box<dyn Future<Output = Result<Response, Box<dyn Error + Send + Sync>>> + Send>>
         + Send
         + Sync,
 >;
 
-// Define a simple Request struct
-#[allow(dead_code)] // will be used later
-struct Request {
-    method: Method,
-    path: String,
-    query: HashMap<String, String>,
-    params: HashMap<String, String>,
-}
-
 pub(crate) async fn handle_client(
     stream: TcpStream,
     db: Arc<Mutex<Db>>,
-    key: &str,
+    keys: &ApiKeys,
+    tenants: Arc<Tenants>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let (reader, writer) = stream.into_split();
     let mut reader = BufReader::new(reader);
-    let writer = Arc::new(AsyncMutex::new(BufWriter::new(writer)));
-
-    // Read the request line
-    let mut request_line = String::new();
-    let bytes_read = reader.read_line(&mut request_line).await?;
-    if bytes_read == 0 {
-        return Ok(());
-    }
-
-    // Parse the request line
-    let request_line = request_line.trim_end();
-    let mut parts = request_line.split_whitespace();
-    let method_str = parts.next();
-    let path_and_query = parts.next();
-    let _version = parts.next();
-
-    if let (Some(method_str), Some(path_and_query)) = (method_str, path_and_query) {
-        // parse the method
-        let method = Method::from_str(method_str);
-        if method.is_none() {
-            // Method isn't Allowed
-            writer.lock().await.get_mut().shutdown().await?;
-            return Ok(());
-        }
-        let method = method.unwrap();
-
-        // parse the URL to handle path and query parameters
-        let url = Url::parse(&format!("http://localhost{}", path_and_query))?;
-
-        let path = url.path().to_string(); //url decode the path
-        let path = percent_encoding::percent_decode_str(&path)
-            .decode_utf8()
-            .unwrap()
-            .to_string();
-
-        let query_pairs = form_urlencoded::parse(url.query().unwrap_or("").as_bytes())
-            .into_owned()
-            .collect::<HashMap<String, String>>();
-
-        // check if the key is provided and valid before proceeding
-        if let Some(k) = query_pairs.get("k") {
-            if k != key {
-                // 403 Forbidden, just close the connection without any response to avoid leaking information
-                writer.lock().await.get_mut().shutdown().await?;
+    let mut writer = BufWriter::new(writer);
+    let routes = build_routes(tenants);
+
+    // serve requests until the client or a response closes the connection
+    loop {
+        let request = match tokio::time::timeout(IDLE_TIMEOUT, protocol::read_request(&mut reader, &mut writer)).await {
+            Err(_) | Ok(Ok(None)) => return Ok(()),
+            Ok(Ok(Some(request))) => request,
+            Ok(Err(ReadError::Status(status))) => {
+                Response::new(status).write(&mut writer, Framing::close()).await?;
                 return Ok(());
             }
-        } else {
-            // 401 Unauthorized, just close the connection without any response to avoid leaking information
-            writer.lock().await.get_mut().shutdown().await?;
-            return Ok(());
+            Ok(Err(ReadError::Io(e))) => return Err(e.into()),
+        };
+
+        let framing = request.framing();
+        let response = respond(&routes, request, db.clone(), keys).await;
+        match response.write(&mut writer, framing).await? {
+            Next::Request => {}
+            Next::Close => return Ok(()),
+            Next::Upgrade(upgrade) => return upgrade(reader, writer).await,
         }
+    }
+}
 
-        let routes = build_routes();
-        if let Some((handler, params)) = find_handler(&routes, &method, &path) {
-            let request = Request {
-                method,
-                path,
-                query: query_pairs,
-                params,
-            };
-            handler(request, writer.clone(), db.clone()).await?;
-        } else {
-            let mut writer = writer.lock().await;
-            writer.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
-            writer.flush().await?;
+// checks the key and runs the handler of the route
+async fn respond(routes: &[Route], mut request: Request, db: Arc<Mutex<Db>>, keys: &ApiKeys) -> Response {
+    // `OPTIONS *` can't have a query, and only lists the methods
+    if request.path == "*" {
+        return Response::new(204).header("Allow", allow_header(&allowed_methods(routes, "*")));
+    }
+
+    let Some(key) = request_key(&request) else {
+        return unauthorized("Missing key, use an `Authorization: Bearer` header or ?k=", None);
+    };
+    let Some(api_key) = keys.find(key) else {
+        return unauthorized("Invalid key", Some("invalid_token"));
+    };
+
+    let allowed = allowed_methods(routes, &request.path);
+    if allowed.is_empty() {
+        return Response::error(404, "Not found");
+    }
+    if request.method == Method::OPTIONS {
+        return Response::new(204).header("Allow", allow_header(&allowed));
+    }
+    // HEAD is answered like GET, without the body
+    let method = match request.method {
+        Method::HEAD => Method::GET,
+        method => method,
+    };
+    let Some((scope, handler, params)) = find_handler(routes, &method, &request.path) else {
+        return Response::error(405, "Method not allowed").header("Allow", allow_header(&allowed));
+    };
+    if !api_key.allows(scope) {
+        return Response::error(403, "The key doesn't have the scope of this route")
+            .header("WWW-Authenticate", "Bearer error=\"insufficient_scope\"");
+    }
+
+    request.params = params;
+    match handler(request, api_key, db).await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error handling request: {:?}", e);
+            Response::error(500, "Internal server error")
         }
-    } else {
-        // bad request (most likely a skill issue)
-        let mut writer = writer.lock().await;
-        writer
-            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
-            .await?;
-        writer.flush().await?;
     }
+}
+
+// the `Authorization: Bearer` key, or the `?k=` one
+fn request_key(request: &Request) -> Option<&str> {
+    let bearer = request.headers.get("authorization").and_then(|authorization| {
+        let (scheme, key) = authorization.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| key.trim())
+    });
+    bearer.or(request.query.get("k").map(String::as_str))
+}
 
-    Ok(())
+fn unauthorized(message: &str, error: Option<&str>) -> Response {
+    let challenge = match error {
+        Some(error) => format!("Bearer realm=\"mail-sink\", error=\"{}\"", error),
+        None => "Bearer realm=\"mail-sink\"".to_string(),
+    };
+    Response::error(401, message).header("WWW-Authenticate", challenge)
 }
 
 // function to build the routing table
-fn build_routes() -> Vec<(Method, String, Handler)> {
+fn build_routes(tenants: Arc<Tenants>) -> Vec<Route> {
     vec![
         (
             Method::GET,
             "/mails/:mail_id".to_string(),
-            Box::new(|request, writer, db| Box::pin(get_mail_handler(request, writer, db))),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_mail_handler(request, key, db))),
         ),
         (
             Method::GET,
             "/mails/to/:email".to_string(),
-            Box::new(|request, writer, db| Box::pin(get_mails_from_to_handler(request, writer, db, true))),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_mails_from_to_handler(request, key, db, AddressField::To))),
         ),
         (
             Method::GET,
             "/mails/from/:email".to_string(),
-            Box::new(|request, writer, db| Box::pin(get_mails_from_to_handler(request, writer, db, false))),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_mails_from_to_handler(request, key, db, AddressField::From))),
+        ),
+        (
+            Method::GET,
+            "/mails/to/:email/wait".to_string(),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(wait_mail_handler(request, key, db))),
+        ),
+        (
+            Method::GET,
+            "/mails/:mail_id/raw".to_string(),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_raw_mail_handler(request, key, db))),
+        ),
+        (
+            Method::GET,
+            "/mails/:mail_id/attachments".to_string(),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_attachments_handler(request, key, db))),
+        ),
+        (
+            Method::GET,
+            "/mails/:mail_id/attachments/:n".to_string(),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_attachment_handler(request, key, db))),
+        ),
+        (
+            Method::GET,
+            "/mails/:mail_id/cid/:content_id".to_string(),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_cid_handler(request, key, db))),
+        ),
+        (
+            Method::GET,
+            "/mails/:mail_id/links".to_string(),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_extracted_handler(request, key, db, true))),
+        ),
+        (
+            Method::GET,
+            "/mails/:mail_id/codes".to_string(),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_extracted_handler(request, key, db, false))),
         ),
         (
             Method::DELETE,
             "/mails/:mail_id".to_string(),
-            Box::new(|request, writer, db| Box::pin(delete_mail_handler(request, writer, db))),
+            Scope::Delete,
+            Box::new(|request, key, db| Box::pin(delete_mail_handler(request, key, db))),
         ),
         (
             Method::GET,
             "/mails".to_string(),
-            Box::new(|request, writer, db| Box::pin(get_mails_handler(request, writer, db))),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(get_mails_handler(request, key, db))),
         ),
         (
             Method::DELETE,
             "/mails".to_string(),
-            Box::new(|_, writer, db| Box::pin(delete_all_mails_handler(writer, db))),
+            Scope::Delete,
+            Box::new(|_, key, db| Box::pin(delete_all_mails_handler(key, db))),
         ),
         (
             Method::DELETE,
             "/mails/to/:email".to_string(),
-            Box::new(|request, writer, db| Box::pin(delete_mails_from_to_handler(request, writer, db, true))),
+            Scope::Delete,
+            Box::new(|request, key, db| Box::pin(delete_mails_from_to_handler(request, key, db, AddressField::To))),
         ),
         (
             Method::DELETE,
             "/mails/from/:email".to_string(),
-            Box::new(|request, writer, db| Box::pin(delete_mails_from_to_handler(request, writer, db, false))),
+            Scope::Delete,
+            Box::new(|request, key, db| Box::pin(delete_mails_from_to_handler(request, key, db, AddressField::From))),
+        ),
+        (
+            Method::GET,
+            "/rejections".to_string(),
+            Scope::Admin,
+            Box::new(|request, key, db| Box::pin(get_rejections_handler(request, key, db))),
+        ),
+        (
+            Method::DELETE,
+            "/rejections".to_string(),
+            Scope::Admin,
+            Box::new(|_, key, db| Box::pin(delete_rejections_handler(key, db))),
+        ),
+        (
+            Method::GET,
+            "/events".to_string(),
+            Scope::Read,
+            Box::new(|request, key, _| Box::pin(events_handler(request, key))),
+        ),
+        (
+            Method::GET,
+            "/events/ws".to_string(),
+            Scope::Read,
+            Box::new(|request, key, _| Box::pin(websocket_handler(request, key))),
         ),
         (
             Method::GET,
             "/info".to_string(),
-            Box::new(|_, writer, db| Box::pin(info_handler(writer, db))),
+            Scope::Read,
+            Box::new(move |_, key, db| Box::pin(info_handler(key, db, tenants.clone()))),
         ),
         (
             Method::GET,
             "/preview/:mail_id".to_string(),
-            Box::new(|request, writer, db| Box::pin(preview_mail_handler(request, writer, db))),
+            Scope::Read,
+            Box::new(|request, key, db| Box::pin(preview_mail_handler(request, key, db))),
         ),
         (
             Method::GET,
             "/panel".to_string(),
-            Box::new(|_, writer, _| Box::pin(panel_handler(writer))),
+            Scope::Read,
+            Box::new(|_, _, _| Box::pin(panel_handler())),
         ),
     ]
 }
 
 // function to find the appropriate handler
 fn find_handler<'a>(
-    routes: &'a [(Method, String, Handler)],
+    routes: &'a [Route],
     method: &Method,
     request_path: &str,
-) -> Option<(&'a Handler, HashMap<String, String>)> {
-    for (route_method, route_path, handler) in routes {
+) -> Option<(Scope, &'a Handler, HashMap<String, String>)> {
+    for (route_method, route_path, scope, handler) in routes {
         if method == route_method {
             if let Some(params) = match_path(route_path, request_path) {
-                return Some((handler, params));
+                return Some((*scope, handler, params));
             }
         }
     }
     None
 }
 
-// function to match paths with parameters
+// the methods with a route for the path, every method for `*`
+fn allowed_methods(routes: &[Route], request_path: &str) -> Vec<Method> {
+    let mut methods = Vec::new();
+    for (method, route_path, _, _) in routes {
+        if (request_path == "*" || match_path(route_path, request_path).is_some()) && !methods.contains(method) {
+            methods.push(*method);
+        }
+    }
+    if methods.contains(&Method::GET) {
+        methods.push(Method::HEAD);
+    }
+    if !methods.is_empty() {
+        methods.push(Method::OPTIONS);
+    }
+    methods
+}
+
+fn allow_header(methods: &[Method]) -> String {
+    methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ")
+}
+
+// function to match paths with parameters, the segments of the request are
+// percent-decoded after splitting it, a `%2F` stays in its segment
 fn match_path(route_path: &str, request_path: &str) -> Option<HashMap<String, String>> {
     let route_parts: Vec<&str> = route_path.trim_end_matches('/').split('/').collect();
     let request_parts: Vec<&str> = request_path.trim_end_matches('/').split('/').collect();
@@ -232,10 +330,11 @@ fn match_path(route_path: &str, request_path: &str) -> Option<HashMap<String, St
     let mut params = HashMap::new();
 
     for (route_part, request_part) in route_parts.iter().zip(request_parts.iter()) {
+        let request_part = percent_encoding::percent_decode_str(request_part).decode_utf8().ok()?;
         if route_part.starts_with(':') {
             let name = route_part.trim_start_matches(':');
             params.insert(name.to_string(), request_part.to_string());
-        } else if route_part != request_part {
+        } else if *route_part != request_part {
             return None;
         }
     }
@@ -243,195 +342,344 @@ fn match_path(route_path: &str, request_path: &str) -> Option<HashMap<String, St
     Some(params)
 }
 
+// the JSON representation of a mail returned by the API
+pub(crate) fn mail_to_json(mail: &Mail) -> Result<Value, Box<dyn Error + Send + Sync>> {
+    let mut json = serde_json::to_value(mail)?;
+    json["data"] = Value::String(String::from_utf8_lossy(&mail.data).to_string());
+    json["headers"] = serde_json::to_value(mail.headers())?;
+    match mail.mime() {
+        Some(content) => {
+            json["body"] = Value::String(content.html.clone().or(content.text.clone()).unwrap_or_default());
+            json["text"] = serde_json::to_value(&content.text)?;
+            json["html"] = serde_json::to_value(&content.html)?;
+            json["attachments"] = serde_json::to_value(&content.attachments)?;
+            json["mime"] = serde_json::to_value(&content.tree)?;
+            json["links"] = serde_json::to_value(extract_links(&content))?;
+            json["codes"] = serde_json::to_value(extract_codes(mail.subject.as_deref(), &content))?;
+        }
+        None => {
+            json["body"] = Value::String(mail.parse_body());
+            json["text"] = Value::Null;
+            json["html"] = Value::Null;
+            json["attachments"] = Value::Array(Vec::new());
+            json["mime"] = Value::Null;
+            json["links"] = Value::Array(Vec::new());
+            json["codes"] = Value::Array(Vec::new());
+        }
+    }
+    json["timestamp"] = Value::Number(serde_json::Number::from_str(&mail.timestamp().to_string())?);
+    Ok(json)
+}
+
+// the address field selected with `?field=`, `None` when it's unknown
+fn address_field(request: &Request, default: AddressField) -> Option<AddressField> {
+    match request.query.get("field") {
+        Some(field) => field.parse().ok(),
+        None => Some(default),
+    }
+}
+
+// the `:mail_id` of the route, `None` when it isn't a number
+fn mail_id(request: &Request) -> Option<u128> {
+    request.params.get("mail_id")?.parse().ok()
+}
+
+// `?limit` and `?offset`, `None` when one isn't a number
+fn pagination(request: &Request) -> Option<(usize, usize)> {
+    let limit = request.query.get("limit").map_or(Ok(10), |limit| limit.parse());
+    let offset = request.query.get("offset").map_or(Ok(0), |offset| offset.parse());
+    Some((limit.ok()?, offset.ok()?))
+}
+
+// the mail, `None` when it doesn't exist or the key can't see it
+async fn get_mail(db: &Mutex<Db>, mail_id: u128, key: &ApiKey) -> Result<Option<Mail>, Box<dyn Error + Send + Sync>> {
+    let db = db.lock().await;
+    let key_bytes = mail_id.to_le_bytes();
+    let mail = db.get(key_bytes)?.and_then(|data| read_stored(&key_bytes, &data));
+    Ok(mail.filter(|mail| key.can_see(mail)))
+}
+
+fn invalid_mail_id() -> Response {
+    Response::error(400, "Invalid mail id")
+}
+
+fn mail_not_found() -> Response {
+    Response::error(404, "Mail not found")
+}
+
 //     HANDLERS     //
 
 async fn get_mail_handler(
     request: Request,
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mail_id = request.params.get("mail_id").unwrap();
-
-    let mail_id = u128::from_str_radix(mail_id, 10).map_err(|_| {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Invalid mail_id",
-        )) as Box<dyn Error + Send + Sync>
-    })?;
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
+
+    match get_mail(&db, mail_id, &key).await? {
+        Some(mail) => Response::json(&mail_to_json(&mail)?),
+        None => Ok(mail_not_found()),
+    }
+}
 
-    let db = db.lock().await;
-    let result = db.get(mail_id.to_le_bytes());
-
-    let mut writer = writer.lock().await;
-
-    if let Ok(Some(data)) = result {
-        let mail: Mail = bincode::deserialize(&data)?;
-        let mut json = serde_json::to_value(&mail)?;
-        json["body"] = Value::String(mail.parse_body());
-        Value::Number(serde_json::Number::from_str(&mail.timestamp().to_string()).unwrap());
-        let json = serde_json::to_string(&json)?;
-
-        writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-        writer
-            .write_all(b"Content-Type: application/json\r\n")
-            .await?;
-        writer
-            .write_all(format!("Content-Length: {}\r\n", json.len()).as_bytes())
-            .await?;
-        writer.write_all(b"\r\n").await?;
-        writer.write_all(json.as_bytes()).await?;
-    } else {
-        writer.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
+async fn get_raw_mail_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
+
+    match get_mail(&db, mail_id, &key).await? {
+        // the message exactly as it was received
+        Some(mail) => Ok(Response::with_body(200, "message/rfc822", mail.data)),
+        None => Ok(mail_not_found()),
     }
+}
 
-    writer.flush().await?;
-    Ok(())
+async fn get_attachments_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
+
+    match get_mail(&db, mail_id, &key).await? {
+        Some(mail) => {
+            let attachments = mail.mime().map(|content| content.attachments).unwrap_or_default();
+            Response::json(&attachments)
+        }
+        None => Ok(mail_not_found()),
+    }
 }
 
-async fn delete_mail_handler(
+async fn get_attachment_handler(
     request: Request,
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mail_id = request.params.get("mail_id").unwrap();
-    let mail_id = u128::from_str_radix(mail_id, 10).map_err(|_| {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Invalid mail_id",
-        )) as Box<dyn Error + Send + Sync>
-    })?;
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
+    let Some(Ok(n)) = request.params.get("n").map(|n| n.parse::<usize>()) else {
+        return Ok(Response::error(400, "Invalid attachment index"));
+    };
+
+    let attachment = get_mail(&db, mail_id, &key)
+        .await?
+        .and_then(|mail| mail.mime())
+        .and_then(|content| content.attachments.into_iter().nth(n));
+    Ok(attachment_response(attachment, false))
+}
 
-    let db = db.lock().await;
-    let result = db.get(mail_id.to_le_bytes());
-
-    let mut writer = writer.lock().await;
-
-    if let Ok(Some(data)) = result {
-        db.remove(mail_id.to_le_bytes()).unwrap();
-        let mail: Mail = bincode::deserialize(&data)?;
-        let mut json = serde_json::to_value(&mail)?;
-        json["body"] = Value::String(mail.parse_body());
-        Value::Number(serde_json::Number::from_str(&mail.timestamp().to_string()).unwrap());
-        let json = serde_json::to_string(&json)?;
-
-        writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-        writer
-            .write_all(b"Content-Type: application/json\r\n")
-            .await?;
-        writer
-            .write_all(format!("Content-Length: {}\r\n", json.len()).as_bytes())
-            .await?;
-        writer.write_all(b"\r\n").await?;
-        writer.write_all(json.as_bytes()).await?;
+async fn get_extracted_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+    links: bool,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
+
+    let Some(mail) = get_mail(&db, mail_id, &key).await? else {
+        return Ok(mail_not_found());
+    };
+    let content = mail.mime().unwrap_or_default();
+    if links {
+        Response::json(&extract_links(&content))
     } else {
-        writer.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
+        Response::json(&extract_codes(mail.subject.as_deref(), &content))
     }
-    writer.flush().await?;
-    Ok(())
+}
+
+async fn get_cid_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
+    let content_id = request.params.get("content_id");
+
+    let attachment = get_mail(&db, mail_id, &key)
+        .await?
+        .and_then(|mail| mail.mime())
+        .and_then(|content| {
+            content
+                .attachments
+                .into_iter()
+                .find(|a| a.content_id.as_ref() == content_id)
+        });
+    Ok(attachment_response(attachment, true))
+}
+
+// the decoded bytes of an attachment, 404 when there is none
+fn attachment_response(attachment: Option<Attachment>, inline: bool) -> Response {
+    let Some(attachment) = attachment else {
+        return Response::error(404, "Attachment not found");
+    };
+
+    let disposition = if inline { "inline" } else { "attachment" };
+    let disposition = match &attachment.filename {
+        Some(filename) => {
+            // keep the header valid whatever the sender put in the name
+            let filename = filename.replace(['"', '\\', '\r', '\n'], "_");
+            format!("{}; filename=\"{}\"", disposition, filename)
+        }
+        None => disposition.to_string(),
+    };
+    // same for the type, it comes from the message. Inline parts are shown on
+    // the origin of the API, only images that can't run scripts keep it
+    let content_type = attachment.content_type.replace(['\r', '\n'], "");
+    let content_type = if !inline || INLINE_TYPES.contains(&content_type.to_lowercase().as_str()) {
+        content_type
+    } else {
+        "application/octet-stream".to_string()
+    };
+
+    Response::with_body(200, &content_type, attachment.data)
+        .header("Content-Disposition", disposition)
+        .header("X-Content-Type-Options", "nosniff")
+}
+
+// the types inline parts are served with, SVG is left out as it can hold scripts
+const INLINE_TYPES: [&str; 7] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+    "image/avif",
+    "image/x-icon",
+];
+
+async fn delete_mail_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
+
+    let Some(mail) = get_mail(&db, mail_id, &key).await? else {
+        return Ok(mail_not_found());
+    };
+    db.lock().await.remove(mail_id.to_le_bytes())?;
+    Response::json(&mail_to_json(&mail)?)
 }
 
 async fn get_mails_handler(
     request: Request,
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let limit = request
-        .query
-        .get("limit")
-        .unwrap_or(&String::from("10"))
-        .parse::<usize>()
-        .unwrap();
-    let offset = request
-        .query
-        .get("offset")
-        .unwrap_or(&String::from("0"))
-        .parse::<usize>()
-        .unwrap();
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some((limit, offset)) = pagination(&request) else {
+        return Ok(Response::error(400, "Invalid limit or offset"));
+    };
 
     let db = db.lock().await;
-    let mut iter = db.iter().rev();
-    let mut mails = Vec::new();
-    let mut count = 0;
+    let mut mails_json = Vec::new();
+
+    let mut skipped = 0;
 
-    for _ in 0..offset {
-        if iter.next().is_none() {
+    for result in db.iter().rev() {
+        if mails_json.len() >= limit {
             break;
         }
-    }
+        let (id, data) = result?;
+        let Some(mail) = read_stored(&id, &data) else {
+            continue;
+        };
 
-    while let Some(result) = iter.next() {
-        let (_, data) = result?;
-        let mail: Mail = bincode::deserialize(&data)?;
-        mails.push(mail);
-        count += 1;
-        if count >= limit {
-            break;
+        if !key.can_see(&mail) {
+            continue;
+        }
+        if skipped < offset {
+            skipped += 1;
+            continue;
         }
+        mails_json.push(mail_to_json(&mail)?);
     }
 
-    let mut mails_json = Vec::new();
-    for mail in &mails {
-        let mut json: Value = serde_json::to_value(mail)?;
-        let parsed_body = mail.parse_body();
-        json["body"] = Value::String(parsed_body);
-        json["timestamp"] =
-            Value::Number(serde_json::Number::from_str(&mail.timestamp().to_string()).unwrap());
-        mails_json.push(json);
-    }
-    let json = serde_json::to_string(&mails_json)?;
-
-    let mut writer = writer.lock().await;
-    writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-    writer
-        .write_all(b"Content-Type: application/json\r\n")
-        .await?;
-    writer
-        .write_all(format!("Content-Length: {}\r\n", json.len()).as_bytes())
-        .await?;
-    writer.write_all(b"\r\n").await?;
-    writer.write_all(json.as_bytes()).await?;
-
-    writer.flush().await?;
-    Ok(())
+    Response::json(&mails_json)
 }
 
 async fn delete_all_mails_handler(
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
     let db = db.lock().await;
-    let count = db.len();
-    db.clear().unwrap();
-
-    let json = format!(r#"{{"deleted":{}}}"#, count);
+    if !key.is_restricted() {
+        let count = db.len();
+        db.clear()?;
+        return Response::json(&json!({ "deleted": count }));
+    }
 
-    let mut writer = writer.lock().await;
-    writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-    writer
-        .write_all(b"Content-Type: application/json\r\n")
-        .await?;
-    writer
-        .write_all(format!("Content-Length: {}\r\n", json.len()).as_bytes())
-        .await?;
-    writer.write_all(b"\r\n").await?;
-    writer.write_all(json.as_bytes()).await?;
+    // only the mails the key can see
+    let mut count = 0;
+    for result in db.iter() {
+        let (id, data) = result?;
+        let Some(mail) = read_stored(&id, &data) else {
+            continue;
+        };
+        if key.can_see(&mail) {
+            db.remove(id)?;
+            count += 1;
+        }
+    }
 
-    writer.flush().await?;
-    Ok(())
+    Response::json(&json!({ "deleted": count }))
 }
 
 async fn info_handler(
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tenants: Arc<Tenants>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    // a tenant key only gets the count of its own tenant
+    let mut tenant_counts: BTreeMap<&str, usize> = tenants
+        .names()
+        .filter(|name| key.tenant.as_deref().is_none_or(|tenant| tenant == *name))
+        .map(|name| (name, 0))
+        .collect();
+
     let db = db.lock().await;
-    let count = db.len();
+    let count = if key.is_restricted() || !tenants.is_empty() {
+        let mut count = 0;
+        for result in db.iter() {
+            let (id, data) = result?;
+            let Some(mail) = read_stored(&id, &data) else {
+                continue;
+            };
+            if key.can_see(&mail) {
+                count += 1;
+                // a mail of several tenants counts for each of them
+                for tenant in &mail.tenants {
+                    if let Some(tenant_count) = tenant_counts.get_mut(tenant.as_str()) {
+                        *tenant_count += 1;
+                    }
+                }
+            }
+        }
+        count
+    } else {
+        db.len()
+    };
 
-    let database_disk_usage = db.size_on_disk().unwrap();
+    let database_disk_usage = db.size_on_disk()?;
+    drop(db);
     let pid = std::process::id();
-    let mut process = Process::new(pid).unwrap();
+    let mut process = Process::new(pid)?;
 
-    let mem_info = process.memory_info().unwrap();
+    let mem_info = process.memory_info()?;
     let mem_usage = mem_info.rss();
 
     let mut system = System::new();
@@ -444,7 +692,7 @@ async fn info_handler(
     // this is a bit tricky, but it's needed to get the correct CPU usage because
     // refresh_cpu_usage() consumes lots of CPU for a really short time
     tokio::time::sleep(std::time::Duration::from_millis(20)).await;
-    let cpu_usage = process.cpu_percent().unwrap();
+    let cpu_usage = process.cpu_percent()?;
 
     let machine_cpu_usage = system.global_cpu_usage();
 
@@ -457,8 +705,9 @@ async fn info_handler(
         .sum();
     let free_space: u64 = disks.iter().map(|disk| disk.available_space()).sum();
 
-    let json = json!({
+    Response::json(&json!({
         "mail_count": count,
+        "tenants": tenant_counts,
         "database_disk_usage": database_disk_usage,
         "memory_usage": mem_usage,
         "machine_memory_usage": machine_memory_usage,
@@ -468,219 +717,380 @@ async fn info_handler(
         "max_cpu_usage": max_cpu_usage,
         "disk_usage": disk_usage,
         "free_space": free_space,
-    });
-
-    let json = serde_json::to_string(&json)?;
-
-    let mut writer = writer.lock().await;
-    writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-    writer
-        .write_all(b"Content-Type: application/json\r\n")
-        .await?;
-    writer
-        .write_all(format!("Content-Length: {}\r\n", json.len()).as_bytes())
-        .await?;
-    writer.write_all(b"\r\n").await?;
-
-    writer.write_all(json.as_bytes()).await?;
-
-    writer.flush().await?;
-    Ok(())
+    }))
 }
 
 async fn preview_mail_handler(
     request: Request,
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mail_id = request.params.get("mail_id").unwrap();
-    let mail_id = u128::from_str_radix(mail_id, 10).map_err(|_| {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Invalid mail_id",
-        )) as Box<dyn Error + Send + Sync>
-    })?;
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let Some(mail_id) = mail_id(&request) else {
+        return Ok(invalid_mail_id());
+    };
 
-    let db = db.lock().await;
-    let result = db.get(mail_id.to_le_bytes());
-
-    let mut writer = writer.lock().await;
-
-    if result.is_err() {
-        writer
-            .write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n")
-            .await?;
-        writer.flush().await?;
-        return Ok(());
-    } else if result.unwrap().is_none() {
-        writer.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
-        writer.flush().await?;
-        return Ok(());
+    if get_mail(&db, mail_id, &key).await?.is_none() {
+        return Ok(mail_not_found());
     }
 
     // return preview.html
     let body = include_bytes!("pages/preview.html");
-    writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-    writer.write_all(b"Content-Type: text/html\r\n").await?;
-    writer
-        .write_all(format!("Content-Length: {}\r\n", body.len()).as_bytes())
-        .await?;
-
-    writer.write_all(b"\r\n").await?;
-    writer.write_all(body).await?;
-    writer.flush().await?;
-    Ok(())
+    Ok(Response::with_body(200, "text/html; charset=utf-8", body.as_slice()))
 }
 
-async fn panel_handler(
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn panel_handler() -> Result<Response, Box<dyn Error + Send + Sync>> {
     let body = include_bytes!("pages/panel.html");
-
-    let mut writer = writer.lock().await;
-    writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-    writer.write_all(b"Content-Type: text/html\r\n").await?;
-    writer
-        .write_all(format!("Content-Length: {}\r\n", body.len()).as_bytes())
-        .await?;
-
-    writer.write_all(b"\r\n").await?;
-    writer.write_all(body).await?;
-    writer.flush().await?;
-    Ok(())
+    Ok(Response::with_body(200, "text/html; charset=utf-8", body.as_slice()))
 }
 
 async fn get_mails_from_to_handler(
     request: Request,
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-    to: bool,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let email_filter = request.params.get("email").unwrap().to_lowercase();
-    let limit = request
-        .query
-        .get("limit")
-        .unwrap_or(&String::from("10"))
-        .parse::<usize>()
-        .unwrap();
-    let offset = request
-        .query
-        .get("offset")
-        .unwrap_or(&String::from("0"))
-        .parse::<usize>()
-        .unwrap();
+    field: AddressField,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let email_filter = request.params["email"].to_lowercase();
+    let Some(field) = address_field(&request, field) else {
+        return Ok(Response::error(400, "Unknown address field"));
+    };
+    let Some((limit, offset)) = pagination(&request) else {
+        return Ok(Response::error(400, "Invalid limit or offset"));
+    };
 
     let db = db.lock().await;
-    let mut iter = db.iter().rev();
-    let mut mails = Vec::new();
-    let mut count = 0;
+    let mut mails_json = Vec::new();
+    let mut skipped = 0;
 
-    for _ in 0..offset {
-        if iter.next().is_none() {
+    for result in db.iter().rev() {
+        if mails_json.len() >= limit {
             break;
         }
+        let (id, data) = result?;
+        let Some(mail) = read_stored(&id, &data) else {
+            continue;
+        };
+
+        if !mail.has_address(field, &email_filter) || !key.can_see(&mail) {
+            continue;
+        }
+        // the offset applies to the matching mails only
+        if skipped < offset {
+            skipped += 1;
+            continue;
+        }
+        mails_json.push(mail_to_json(&mail)?);
     }
 
-    while let Some(result) = iter.next() {
-        let (_, data) = result?;
-        let mail: Mail = bincode::deserialize(&data)?;
+    Response::json(&mails_json)
+}
 
-        if to {
-            if mail.to.iter().any(|to_email| to_email.to_lowercase() == email_filter) {
-                mails.push(mail);
-                count += 1;
-                if count >= limit {
-                    break;
+async fn wait_mail_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let email_filter = request.params["email"].to_lowercase();
+    let Some(field) = address_field(&request, AddressField::To) else {
+        return Ok(Response::error(400, "Unknown address field"));
+    };
+    let Ok(timeout) = parse_duration(request.query.get("timeout").map_or("30s", String::as_str)) else {
+        return Ok(Response::error(400, "Invalid timeout"));
+    };
+    if timeout > MAX_WAIT {
+        return Ok(Response::error(400, "The timeout can't be above 10 minutes"));
+    }
+    // without `after`, only the mails received from now on are returned
+    let after = match request.query.get("after") {
+        Some(after) => match after.parse::<u128>() {
+            Ok(after) => Some(after),
+            Err(_) => return Ok(Response::error(400, "Invalid mail id in after")),
+        },
+        None => None,
+    };
+
+    // subscribe before looking at the stored mails, so a mail stored in
+    // between isn't missed
+    let mut stored_mails = events::subscribe();
+    let mut mail = match after {
+        Some(after) => find_mail_after(&db, &key, field, &email_filter, after).await?,
+        None => None,
+    };
+    // ids only grow, the mails received from now on have a bigger one
+    let after = after.unwrap_or_else(crate::snowflake::next);
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    while mail.is_none() {
+        match tokio::time::timeout_at(deadline, stored_mails.recv()).await {
+            Err(_) => break,
+            Ok(Ok(stored)) => {
+                if stored.id > after
+                    && stored.has_address(field, &email_filter)
+                    && key.can_see(&stored)
+                {
+                    mail = Some(mail_to_json(&stored)?);
                 }
             }
-        } else {
-            if mail.from.iter().any(|to_email| to_email.to_lowercase() == email_filter) {
-                mails.push(mail);
-                count += 1;
-                if count >= limit {
-                    break;
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => {
+                // some mails were missed, they are in the database
+                mail = find_mail_after(&db, &key, field, &email_filter, after).await?;
+            }
+            Ok(Err(broadcast::error::RecvError::Closed)) => break,
+        }
+    }
+
+    match mail {
+        Some(mail) => Response::json(&mail),
+        None => Ok(Response::error(408, "No mail received before the timeout")),
+    }
+}
+
+// the `?to=` and `?from=` filters of the event streams, limited to the mails
+// the key can see
+fn event_filter(request: &Request, key: Arc<ApiKey>) -> impl Fn(&Mail) -> bool {
+    let to = request.query.get("to").map(|to| to.to_lowercase());
+    let from = request.query.get("from").map(|from| from.to_lowercase());
+    move |mail| {
+        key.can_see(mail)
+            && to.as_ref().is_none_or(|to| mail.has_address(AddressField::To, to))
+            && from.as_ref().is_none_or(|from| mail.has_address(AddressField::From, from))
+    }
+}
+
+// what the event streams send for each mail, the full mail is at /mails/<id>
+fn mail_summary(mail: &Mail) -> Value {
+    json!({
+        "id": mail.id,
+        "timestamp": mail.timestamp(),
+        "subject": mail.subject,
+        "from": mail.from,
+        "to": mail.to,
+        "envelope_from": mail.envelope_from,
+        "envelope_to": mail.envelope_to,
+    })
+}
+
+async fn events_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let filter = event_filter(&request, key);
+    let mut stored_mails = events::subscribe();
+
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>(16);
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                // the client is gone
+                _ = sender.closed() => return,
+                event = tokio::time::timeout(KEEP_ALIVE, stored_mails.recv()) => event,
+            };
+            let event = match event {
+                // a comment keeps proxies from closing an idle stream
+                Err(_) => ": keep-alive\n\n".to_string(),
+                Ok(Ok(mail)) if filter(&mail) => {
+                    format!("event: mail\nid: {}\ndata: {}\n\n", mail.id, mail_summary(&mail))
+                }
+                Ok(Ok(_)) => continue,
+                Ok(Err(broadcast::error::RecvError::Lagged(count))) => {
+                    format!("event: lagged\ndata: {}\n\n", count)
                 }
+                Ok(Err(broadcast::error::RecvError::Closed)) => return,
+            };
+            if sender.send(event.into_bytes()).await.is_err() {
+                return;
             }
         }
+    });
+
+    Ok(Response::stream("text/event-stream", receiver).header("Cache-Control", "no-cache"))
+}
+
+async fn websocket_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let websocket_key = match request.headers.get("sec-websocket-key") {
+        Some(websocket_key)
+            if request.has_token("upgrade", "websocket") && request.has_token("connection", "upgrade") =>
+        {
+            websocket_key
+        }
+        _ => return Ok(Response::error(400, "Expected a WebSocket handshake")),
+    };
+    if request.headers.get("sec-websocket-version").map(String::as_str) != Some("13") {
+        return Ok(Response::error(426, "Unsupported WebSocket version").header("Sec-WebSocket-Version", "13"));
     }
 
-    let mut mails_json = Vec::new();
-    for mail in &mails {
-        let mut json: Value = serde_json::to_value(mail)?;
-        let parsed_body = mail.parse_body();
-        json["body"] = Value::String(parsed_body);
-        json["timestamp"] =
-            Value::Number(serde_json::Number::from_str(&mail.timestamp().to_string()).unwrap());
-        mails_json.push(json);
-    }
-    let json = serde_json::to_string(&mails_json)?;
-
-    let mut writer = writer.lock().await;
-    writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-    writer
-        .write_all(b"Content-Type: application/json\r\n")
-        .await?;
-    writer
-        .write_all(format!("Content-Length: {}\r\n", json.len()).as_bytes())
-        .await?;
-    writer.write_all(b"\r\n").await?;
-    writer.write_all(json.as_bytes()).await?;
-
-    writer.flush().await?;
-    Ok(())
+    let filter = event_filter(&request, key);
+    let stored_mails = events::subscribe();
+    let accept = websocket::accept_key(websocket_key);
+
+    Ok(Response::upgrade(
+        "websocket",
+        Box::new(move |reader, writer| Box::pin(stream_websocket(reader, writer, filter, stored_mails))),
+    )
+    .header("Sec-WebSocket-Accept", accept))
+}
+
+// sends the summary of the mails accepted by `filter` until the client leaves
+async fn stream_websocket(
+    mut reader: protocol::Reader,
+    mut writer: protocol::Writer,
+    filter: impl Fn(&Mail) -> bool,
+    mut stored_mails: broadcast::Receiver<Arc<Mail>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // frames are read in their own task, reading one isn't cancel safe
+    let (frame_sender, mut frames) = mpsc::channel(8);
+    let reader_task = tokio::spawn(async move {
+        while let Ok(frame) = websocket::read_frame(&mut reader).await {
+            if frame_sender.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let result: Result<(), Box<dyn Error + Send + Sync>> = async {
+        loop {
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some((websocket::PING, payload)) => {
+                        websocket::write_frame(&mut writer, websocket::PONG, &payload).await?;
+                    }
+                    Some((websocket::CLOSE, payload)) => {
+                        // echo the status code, then the connection is done
+                        let code = payload.get(..2).unwrap_or_default();
+                        websocket::write_frame(&mut writer, websocket::CLOSE, code).await?;
+                        return Ok(());
+                    }
+                    Some(_) => {}
+                    // the client is gone
+                    None => return Ok(()),
+                },
+                mail = stored_mails.recv() => match mail {
+                    Ok(mail) if filter(&mail) => {
+                        let json = mail_summary(&mail).to_string();
+                        websocket::write_frame(&mut writer, websocket::TEXT, json.as_bytes()).await?;
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+
+    reader_task.abort();
+    result
+}
+
+// the oldest stored mail matching the filter with an id greater than `after`
+async fn find_mail_after(
+    db: &Mutex<Db>,
+    key: &ApiKey,
+    field: AddressField,
+    email_filter: &str,
+    after: u128,
+) -> Result<Option<Value>, Box<dyn Error + Send + Sync>> {
+    let db = db.lock().await;
+    let mut found: Option<Mail> = None;
+
+    for result in db.iter() {
+        let (id, data) = result?;
+        let Some(mail) = read_stored(&id, &data) else {
+            continue;
+        };
+        if mail.id > after
+            && mail.has_address(field, email_filter)
+            && key.can_see(&mail)
+            && found.as_ref().is_none_or(|found| mail.id < found.id)
+        {
+            found = Some(mail);
+        }
+    }
+
+    found.as_ref().map(mail_to_json).transpose()
 }
 
 async fn delete_mails_from_to_handler(
     request: Request,
-    writer: Arc<AsyncMutex<BufWriter<tokio::net::tcp::OwnedWriteHalf>>>,
+    key: Arc<ApiKey>,
     db: Arc<Mutex<Db>>,
-    to: bool,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let email_filter = request.params.get("email").unwrap().to_lowercase();
+    field: AddressField,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let email_filter = request.params["email"].to_lowercase();
+    let Some(field) = address_field(&request, field) else {
+        return Ok(Response::error(400, "Unknown address field"));
+    };
 
     let db = db.lock().await;
-    let mut iter = db.iter().rev();
     let mut mail_ids = Vec::new();
 
+    for result in db.iter().rev() {
+        let (id, data) = result?;
+        let Some(mail) = read_stored(&id, &data) else {
+            continue;
+        };
 
-    while let Some(result) = iter.next() {
-        let (_, data) = result?;
-        let mail: Mail = bincode::deserialize(&data)?;
-
-        if to {
-            if mail.to.iter().any(|to_email| to_email.to_lowercase() == email_filter) {
-                mail_ids.push(mail.id);
-            }
-        } else {
-            if mail.from.iter().any(|to_email| to_email.to_lowercase() == email_filter) {
-                mail_ids.push(mail.id);
-            }
+        if mail.has_address(field, &email_filter) && key.can_see(&mail) {
+            mail_ids.push(mail.id);
         }
     }
 
     let count = mail_ids.len();
 
     for id in mail_ids {
-        match db.remove(id.to_le_bytes()) {
-            Ok(_) => {}
-            Err(e) => eprint!("Failed te detelet mais {}: {}", id, e)
+        if let Err(e) = db.remove(id.to_le_bytes()) {
+            eprintln!("Failed to delete mail {}: {}", id, e);
         }
     }
 
+    Response::json(&json!({ "deleted": count }))
+}
 
-    let json = format!(r#"{{"deleted":{}}}"#, count);
+async fn get_rejections_handler(
+    request: Request,
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    if key.is_restricted() {
+        return Ok(rejections_forbidden());
+    }
+    let Some((limit, offset)) = pagination(&request) else {
+        return Ok(Response::error(400, "Invalid limit or offset"));
+    };
+
+    let db = db.lock().await;
+    let tree = db.open_tree(REJECTIONS_TREE)?;
+    let mut rejections_json = Vec::new();
+
+    for result in tree.iter().rev().skip(offset).take(limit) {
+        let (_, data) = result?;
+        let rejection: Rejection = bincode::deserialize(&data)?;
+        let mut json = serde_json::to_value(&rejection)?;
+        json["timestamp"] = Value::Number(serde_json::Number::from_str(&rejection.timestamp().to_string())?);
+        rejections_json.push(json);
+    }
+
+    Response::json(&rejections_json)
+}
 
-    let mut writer = writer.lock().await;
-    writer.write_all(b"HTTP/1.1 200 OK\r\n").await?;
-    writer
-        .write_all(b"Content-Type: application/json\r\n")
-        .await?;
-    writer
-        .write_all(format!("Content-Length: {}\r\n", json.len()).as_bytes())
-        .await?;
-    writer.write_all(b"\r\n").await?;
-    writer.write_all(json.as_bytes()).await?;
+async fn delete_rejections_handler(
+    key: Arc<ApiKey>,
+    db: Arc<Mutex<Db>>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    if key.is_restricted() {
+        return Ok(rejections_forbidden());
+    }
+    let db = db.lock().await;
+    let tree = db.open_tree(REJECTIONS_TREE)?;
+    let count = tree.len();
+    tree.clear()?;
 
-    writer.flush().await?;
-    Ok(())
-}
\ No newline at end of file
+    Response::json(&json!({ "deleted": count }))
+}
+
+// the rejections are shared by every tenant and domain, and hold the
+// addresses of the others, so only unrestricted keys can use them
+fn rejections_forbidden() -> Response {
+    Response::error(403, "The rejections are only available to keys without a tenant or domains")
+}
@@ -0,0 +1,210 @@
+use crate::smtp::mail::Mail;
+use crate::smtp::rules::glob_match;
+use crate::tenant::Tenants;
+use crate::SharedError;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// What a key may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// every `GET` route but the rejections
+    Read,
+    /// the `DELETE` routes of the mails
+    Delete,
+    /// everything, the rejections included
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "read" => Ok(Scope::Read),
+            "delete" => Ok(Scope::Delete),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("Unknown API key scope `{}`, expected `read`, `delete` or `admin`", s)),
+        }
+    }
+}
+
+/// A named API key, like `ci:s3cret:read,delete:ci.test,*.qa.test`.
+///
+/// The syntax is `NAME:KEY:SCOPE[,SCOPE...][:DOMAIN,...][:TENANT]`. With
+/// domains, the key only sees the mails delivered to one of them, `*` in a
+/// domain matches any characters. With a tenant, like `ci:s3cret:read::ci`, it
+/// only sees the mails of the tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub name: String,
+    key: String,
+    pub scopes: Vec<Scope>,
+    /// recipient domains, empty for every mail
+    pub domains: Vec<String>,
+    /// the only tenant whose mails the key sees
+    pub tenant: Option<String>,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "Wrong API key, expected `NAME:KEY:SCOPE[,SCOPE...][:DOMAIN,...][:TENANT]`: `{}`",
+                s
+            )
+        };
+        let mut parts = s.trim().split(':');
+        let (Some(name), Some(key), Some(scopes), domains, tenant, None) =
+            (parts.next(), parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(err());
+        };
+        if name.is_empty() || key.is_empty() {
+            return Err(err());
+        }
+
+        let scopes = scopes
+            .split(',')
+            .map(|scope| scope.trim().parse())
+            .collect::<Result<Vec<Scope>, _>>()?;
+        let domains = domains
+            .unwrap_or_default()
+            .split(',')
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        let tenant = tenant.map(str::trim).filter(|tenant| !tenant.is_empty());
+
+        Ok(Self {
+            name: name.to_string(),
+            key: key.to_string(),
+            scopes,
+            domains,
+            tenant: tenant.map(str::to_string),
+        })
+    }
+}
+
+impl ApiKey {
+    /// A key allowed to do anything, for `--key`.
+    pub fn admin(key: &str) -> Self {
+        Self {
+            name: "default".to_string(),
+            key: key.to_string(),
+            scopes: vec![Scope::Admin],
+            domains: Vec::new(),
+            tenant: None,
+        }
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    /// Whether the key is limited to a tenant or some recipient domains.
+    pub fn is_restricted(&self) -> bool {
+        self.tenant.is_some() || !self.domains.is_empty()
+    }
+
+    /// Whether `address` is in one of the domains of the key.
+    pub fn can_see_address(&self, address: &str) -> bool {
+        if self.domains.is_empty() {
+            return true;
+        }
+        let Some((_, domain)) = address.rsplit_once('@') else {
+            return false;
+        };
+        let domain = domain.to_lowercase();
+        self.domains.iter().any(|pattern| glob_match(pattern, &domain))
+    }
+
+    /// Whether the mail belongs to the tenant of the key and was delivered to
+    /// one of its domains, the headers are ignored as any sender can write them.
+    /// Mails without a tenant, like the ones stored before `--tenant` was set,
+    /// are never seen by a tenant key.
+    pub fn can_see(&self, mail: &Mail) -> bool {
+        self.tenant.as_ref().is_none_or(|tenant| mail.tenants.contains(tenant))
+            && (self.domains.is_empty() || mail.envelope_to.iter().any(|to| self.can_see_address(to)))
+    }
+}
+
+/// The keys accepted by the API.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: Vec<Arc<ApiKey>>,
+    /// the admin key from `--key`, or the default one
+    pub default_key: Option<String>,
+}
+
+impl ApiKeys {
+    /// Parses `--api-key` values and the lines of `--api-keys-file`, empty lines
+    /// and lines starting with `#` are ignored. `--key` is added as an admin
+    /// key, it defaults to `prouteur` when no other key is configured. The
+    /// tenant of a key must be one of `tenants`.
+    pub fn parse(
+        keys: &[String],
+        file: Option<&Path>,
+        default_key: Option<&str>,
+        tenants: &Tenants,
+    ) -> Result<Self, SharedError> {
+        let mut lines = keys.to_vec();
+        if let Some(file) = file {
+            let content = std::fs::read_to_string(file)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+            lines.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+
+        let mut keys = lines
+            .iter()
+            .map(|line| line.parse::<ApiKey>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(key) = keys
+            .iter()
+            .find(|key| key.tenant.as_ref().is_some_and(|tenant| !tenants.contains(tenant)))
+        {
+            return Err(format!(
+                "The API key `{}` belongs to the unknown tenant `{}`, add it with --tenant",
+                key.name,
+                key.tenant.as_deref().unwrap_or_default()
+            )
+            .into());
+        }
+        let default_key = match default_key {
+            Some(key) => Some(key),
+            None if keys.is_empty() => Some(DEFAULT_KEY),
+            None => None,
+        };
+        keys.extend(default_key.map(ApiKey::admin));
+        let keys = keys.into_iter().map(Arc::new).collect();
+
+        Ok(Self {
+            keys,
+            default_key: default_key.map(str::to_string),
+        })
+    }
+
+    /// The key matching `key`, compared in constant time.
+    pub fn find(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys
+            .iter()
+            .find(|api_key| constant_time_eq(api_key.key.as_bytes(), key.as_bytes()))
+            .cloned()
+    }
+}
+
+/// The key used when none is configured.
+pub const DEFAULT_KEY: &str = "prouteur";
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
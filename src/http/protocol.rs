@@ -0,0 +1,504 @@
+use crate::SharedError;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+use url::form_urlencoded;
+use url::Url;
+
+pub type Reader = BufReader<OwnedReadHalf>;
+pub type Writer = BufWriter<OwnedWriteHalf>;
+
+/// Longest request line or header line accepted, CRLF included.
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+/// Biggest request body accepted, bigger ones are refused with 413.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    GET,
+    HEAD,
+    POST,
+    PUT,
+    DELETE,
+    OPTIONS,
+}
+
+impl Method {
+    /// Methods are case-sensitive, `get` isn't `GET`.
+    pub fn from_str(method: &str) -> Option<Method> {
+        match method {
+            "GET" => Some(Method::GET),
+            "HEAD" => Some(Method::HEAD),
+            "POST" => Some(Method::POST),
+            "PUT" => Some(Method::PUT),
+            "DELETE" => Some(Method::DELETE),
+            "OPTIONS" => Some(Method::OPTIONS),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::HEAD => "HEAD",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::OPTIONS => "OPTIONS",
+        }
+    }
+}
+
+pub struct Request {
+    pub method: Method,
+    /// still percent-encoded, so an encoded `/` doesn't split a segment, `*`
+    /// for `OPTIONS *`
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// the `:name` segments of the matched route
+    pub params: HashMap<String, String>,
+    /// names are lowercase, repeated headers are joined with `, `
+    pub headers: HashMap<String, String>,
+    /// decoded when sent chunked
+    pub body: Vec<u8>,
+    /// 0 for HTTP/1.0, 1 for HTTP/1.1
+    pub minor_version: u8,
+}
+
+impl Request {
+    /// Whether the comma separated `name` header has `token`, like
+    /// `Connection: keep-alive, Upgrade`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .get(name)
+            .is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    }
+
+    pub fn framing(&self) -> Framing {
+        let keep_alive = if self.minor_version == 0 {
+            self.has_token("connection", "keep-alive")
+        } else {
+            !self.has_token("connection", "close")
+        };
+        Framing {
+            head: self.method == Method::HEAD,
+            chunked: self.minor_version > 0,
+            keep_alive,
+        }
+    }
+}
+
+/// How the response to a request is sent.
+#[derive(Debug, Clone, Copy)]
+pub struct Framing {
+    /// headers only, for `HEAD`
+    pub head: bool,
+    /// the client understands chunked bodies
+    pub chunked: bool,
+    pub keep_alive: bool,
+}
+
+impl Framing {
+    /// For requests that couldn't be read, the connection is closed after the
+    /// response.
+    pub fn close() -> Self {
+        Self {
+            head: false,
+            chunked: false,
+            keep_alive: false,
+        }
+    }
+}
+
+/// Why a request couldn't be read.
+#[derive(Debug)]
+pub enum ReadError {
+    /// the connection failed or was closed in the middle of a request
+    Io(io::Error),
+    /// the request is invalid, it's answered with this status
+    Status(u16),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+/// Reads the next request, `None` when the client closed the connection
+/// between requests.
+pub async fn read_request(reader: &mut Reader, writer: &mut Writer) -> Result<Option<Request>, ReadError> {
+    // empty lines before a request are ignored, RFC 9112 section 2.2
+    let request_line = loop {
+        match read_line(reader, 414).await? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ReadError::Status(400));
+    };
+    let minor_version = match version {
+        "HTTP/1.1" => 1,
+        "HTTP/1.0" => 0,
+        _ if version.starts_with("HTTP/") => return Err(ReadError::Status(505)),
+        _ => return Err(ReadError::Status(400)),
+    };
+    let method = Method::from_str(method).ok_or(ReadError::Status(501))?;
+
+    let (path, query) = parse_target(method, target).ok_or(ReadError::Status(400))?;
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    // lines, repeated headers are joined but count for each line
+    let mut count = 0;
+    loop {
+        let line = read_line(reader, 431).await?.ok_or(ReadError::Status(400))?;
+        if line.is_empty() {
+            break;
+        }
+        count += 1;
+        if count > MAX_HEADERS {
+            return Err(ReadError::Status(431));
+        }
+        // no space before the colon, and no obsolete line folding
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ReadError::Status(400));
+        };
+        if name.is_empty() || name.contains([' ', '\t']) {
+            return Err(ReadError::Status(400));
+        }
+        let value = value.trim_matches([' ', '\t']);
+        headers
+            .entry(name.to_lowercase())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    if minor_version == 1 && !headers.contains_key("host") {
+        return Err(ReadError::Status(400));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        query,
+        params: HashMap::new(),
+        headers,
+        body: Vec::new(),
+        minor_version,
+    };
+
+    let chunked = match request.headers.get("transfer-encoding") {
+        None => false,
+        // a length would be ambiguous, RFC 9112 section 6.3
+        Some(_) if request.headers.contains_key("content-length") => return Err(ReadError::Status(400)),
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => true,
+        Some(_) => return Err(ReadError::Status(501)),
+    };
+    let length = match request.headers.get("content-length") {
+        Some(length) if !chunked => {
+            let length = parse_length(length).ok_or(ReadError::Status(400))?;
+            if length > MAX_BODY {
+                return Err(ReadError::Status(413));
+            }
+            length
+        }
+        _ => 0,
+    };
+
+    if (chunked || length > 0) && minor_version == 1 && request.has_token("expect", "100-continue") {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        writer.flush().await?;
+    }
+
+    if chunked {
+        request.body = read_chunked_body(reader).await?;
+    } else if length > 0 {
+        read_body(reader, length, &mut request.body).await?;
+    }
+
+    Ok(Some(request))
+}
+
+// the path and the query of the request target
+fn parse_target(method: Method, target: &str) -> Option<(String, HashMap<String, String>)> {
+    if target == "*" {
+        return (method == Method::OPTIONS).then(|| ("*".to_string(), HashMap::new()));
+    }
+    let url = if target.starts_with('/') {
+        Url::parse(&format!("http://localhost{}", target)).ok()?
+    } else {
+        // absolute form, like through a proxy
+        Url::parse(target)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))?
+    };
+
+    let path = url.path().to_string();
+    let query = form_urlencoded::parse(url.query().unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+    Some((path, query))
+}
+
+// repeated identical lengths are allowed, RFC 9110 section 8.6
+fn parse_length(value: &str) -> Option<usize> {
+    let mut lengths = value.split(',').map(|length| {
+        let length = length.trim();
+        if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        length.parse::<usize>().ok()
+    });
+    let first = lengths.next()??;
+    for length in lengths {
+        if length? != first {
+            return None;
+        }
+    }
+    Some(first)
+}
+
+// a line without its CRLF, `None` at the end of the stream, `too_long` is the
+// status sent back when it exceeds MAX_LINE
+async fn read_line(reader: &mut Reader, too_long: u16) -> Result<Option<String>, ReadError> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE as u64).read_until(b'\n', &mut line).await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return if line.len() + 1 >= MAX_LINE {
+            Err(ReadError::Status(too_long))
+        } else {
+            Err(ReadError::Io(io::ErrorKind::UnexpectedEof.into()))
+        };
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    // obsolete non-ASCII bytes in header values are replaced
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+async fn read_chunked_body(reader: &mut Reader) -> Result<Vec<u8>, ReadError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader, 400).await?.ok_or(ReadError::Status(400))?;
+        // chunk extensions are ignored
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ReadError::Status(400));
+        }
+        // too many digits for a usize is too big as well
+        let size = usize::from_str_radix(size, 16).map_err(|_| ReadError::Status(413))?;
+        if size == 0 {
+            break;
+        }
+        if size > MAX_BODY - body.len() {
+            return Err(ReadError::Status(413));
+        }
+
+        read_body(reader, size, &mut body).await?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(ReadError::Status(400));
+        }
+    }
+
+    // trailers are ignored
+    while !read_line(reader, 431).await?.ok_or(ReadError::Status(400))?.is_empty() {}
+    Ok(body)
+}
+
+// appends `size` bytes to `body`, it only grows with the bytes received so a
+// client announcing a big body and sending nothing costs nothing
+async fn read_body(reader: &mut Reader, size: usize, body: &mut Vec<u8>) -> Result<(), ReadError> {
+    let read = (&mut *reader).take(size as u64).read_to_end(body).await?;
+    if read < size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Takes over the connection after a `101 Switching Protocols` response.
+pub type Upgrade =
+    Box<dyn FnOnce(Reader, Writer) -> Pin<Box<dyn Future<Output = Result<(), SharedError>> + Send>> + Send>;
+
+pub enum Body {
+    Full(Vec<u8>),
+    /// sent chunked as it comes, until the sender is dropped
+    Stream(mpsc::Receiver<Vec<u8>>),
+    Upgrade(Upgrade),
+}
+
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+/// What happens to the connection after a response.
+pub enum Next {
+    Request,
+    Close,
+    /// hand the connection over
+    Upgrade(Upgrade),
+}
+
+impl Response {
+    /// A response without a body.
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Body::Full(Vec::new()),
+        }
+    }
+
+    pub fn with_body(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self::new(status)
+            .header("Content-Type", content_type)
+            .body(Body::Full(body.into()))
+    }
+
+    pub fn json(value: &impl Serialize) -> Result<Self, SharedError> {
+        Ok(Self::with_body(200, "application/json", serde_json::to_vec(value)?))
+    }
+
+    /// `{"error": message}` with the given status.
+    pub fn error(status: u16, message: &str) -> Self {
+        let body = serde_json::json!({ "error": message }).to_string();
+        Self::with_body(status, "application/json", body)
+    }
+
+    pub fn stream(content_type: &str, receiver: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self::new(200)
+            .header("Content-Type", content_type)
+            .body(Body::Stream(receiver))
+    }
+
+    /// Switches the connection to `protocol`, `upgrade` runs once the 101
+    /// response is sent.
+    pub fn upgrade(protocol: &str, upgrade: Upgrade) -> Self {
+        Self::new(101)
+            .header("Upgrade", protocol)
+            .header("Connection", "Upgrade")
+            .body(Body::Upgrade(upgrade))
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    fn body(mut self, body: Body) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub async fn write(self, writer: &mut Writer, framing: Framing) -> io::Result<Next> {
+        let mut keep_alive = framing.keep_alive;
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        head.push_str(&format!("Date: {}\r\n", httpdate::fmt_http_date(SystemTime::now())));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        match &self.body {
+            // no length for statuses that can't have a body
+            Body::Full(_) if self.status < 200 || self.status == 204 || self.status == 304 => {}
+            Body::Full(body) => head.push_str(&format!("Content-Length: {}\r\n", body.len())),
+            Body::Stream(_) if framing.chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+            // HTTP/1.0 clients read until the connection is closed
+            Body::Stream(_) => keep_alive = false,
+            Body::Upgrade(_) => {}
+        }
+        let upgrade = matches!(self.body, Body::Upgrade(_));
+        if !keep_alive && !upgrade {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes()).await?;
+
+        match self.body {
+            Body::Full(body) => {
+                if !framing.head {
+                    writer.write_all(&body).await?;
+                }
+            }
+            Body::Stream(mut receiver) if !framing.head => {
+                writer.flush().await?;
+                while let Some(chunk) = receiver.recv().await {
+                    // an empty chunk would end the body
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    let written = if framing.chunked {
+                        write_chunk(writer, &chunk).await
+                    } else {
+                        writer.write_all(&chunk).await
+                    };
+                    if written.is_err() || writer.flush().await.is_err() {
+                        // the client is gone
+                        return Ok(Next::Close);
+                    }
+                }
+                if framing.chunked {
+                    writer.write_all(b"0\r\n\r\n").await?;
+                }
+            }
+            Body::Stream(_) => {}
+            Body::Upgrade(upgrade) => {
+                writer.flush().await?;
+                return Ok(Next::Upgrade(upgrade));
+            }
+        }
+        writer.flush().await?;
+
+        Ok(if keep_alive { Next::Request } else { Next::Close })
+    }
+}
+
+async fn write_chunk(writer: &mut Writer, chunk: &[u8]) -> io::Result<()> {
+    writer.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+    writer.write_all(chunk).await?;
+    writer.write_all(b"\r\n").await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
@@ -1,10 +1,15 @@
 mod cli;
+mod events;
 mod http;
 mod smtp;
 mod snowflake;
+mod tenant;
 mod tests;
+mod webhook;
+mod websocket;
 
 use crate::cli::*;
+use crate::smtp::TlsMode;
 use clap::{CommandFactory, Parser};
 use clap_help::Printer;
 use sled::Db;
@@ -12,9 +17,8 @@ use std::error::Error;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use tokio::task;
-use tokio_rustls::rustls::ServerConfig;
 
 type SharedError = Box<dyn Error + Send + Sync>;
 
@@ -30,40 +34,83 @@ async fn main() -> Result<(), SharedError> {
         return Ok(());
     }
 
-    let tls_config = Arc::new(smtp::load_tls_config()?);
+    let smtp_listeners = args.smtp_listeners()?;
+    let tls_config = if args.no_tls {
+        if smtp_listeners.iter().any(|l| l.tls_mode == TlsMode::Implicit) {
+            return Err(From::from("`:tls` SMTP ports can't be used with --no-tls"));
+        }
+        None
+    } else {
+        let tls_config = Arc::new(smtp::tls::ReloadableConfig::load_or_generate(
+            &args.tls_cert,
+            &args.tls_key,
+            &args.hostname,
+            args.write_cert,
+        )?);
+        // pick up renewed certificates without restarting the listeners
+        task::spawn(smtp::tls::watch(tls_config.clone()));
+        Some(tls_config)
+    };
+    let smtp_settings = Arc::new(smtp::Settings {
+        hostname: args.hostname.clone(),
+        tls_config,
+        auth_mode: args.auth,
+        credentials: smtp::auth::Credentials::parse(&args.auth_user)?,
+        extensions: args.extensions.iter().copied().collect(),
+        max_size: args.max_size,
+        rules: smtp::rules::Rules::parse(&args.rule, args.rules_file.as_deref())?,
+        greylist: args.greylist.map(|delay| smtp::greylist::Greylist { delay }),
+        tenants: Arc::new(tenant::Tenants::parse(&args.tenant)?),
+    });
+    smtp::extract::set_code_patterns(&args.code_regex)?;
+    let webhooks = args
+        .webhook
+        .iter()
+        .map(|webhook| webhook.parse::<webhook::Webhook>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let api_keys = Arc::new(http::auth::ApiKeys::parse(
+        &args.api_key,
+        args.api_keys_file.as_deref(),
+        args.key.as_deref(),
+        &smtp_settings.tenants,
+    )?);
     let db = Arc::new(Mutex::new(sled::open("db")?));
 
+    if !webhooks.is_empty() {
+        let webhooks = webhook::Webhooks::new(webhooks, args.webhook_secret.clone(), args.webhook_retries);
+        // subscribed before any mail can be stored
+        task::spawn(webhook::run(Arc::new(webhooks), events::subscribe()));
+    }
+
     let db_clone = db.clone();
-    let tls_clone = tls_config.clone();
-    args.smtp_port.split(',')
-        .map(|port| port.parse::<u16>().expect("Wrong ports"))
-        .for_each(|port| {
-            let tls = tls_clone.clone();
+    smtp_listeners
+        .into_iter()
+        .for_each(|listener| {
+            let settings = smtp_settings.clone();
             let db = db_clone.clone();
             task::spawn(
-                    async move { run_smtp_service(tls, db, port).await },
+                    async move { run_smtp_service(settings, db, listener).await },
                 );
         });
 
 
     let db_clone = db.clone();
-    let key = args.key.clone();
+    let keys = api_keys.clone();
+    let tenants = smtp_settings.tenants.clone();
     let service_handle =
-        task::spawn(async move { run_http_service(db_clone, args.http_ports, key.clone()).await });
+        task::spawn(async move { run_http_service(db_clone, args.http_ports, keys, tenants).await });
 
 
 
-    match args.lifetime {
-        Some(lifetime) => {
-            // spawn a new task, me don't need to wait for it
-            task::spawn(run_cleaner_service(db, lifetime));
-        }
-        _ => {}
+    if args.lifetime.is_some() || smtp_settings.greylist.is_some() {
+        // spawn a new task, me don't need to wait for it
+        task::spawn(run_cleaner_service(db, args.lifetime, smtp_settings.greylist));
     }
 
     println!(
         "Panel: http://localhost:{}/panel?k={}",
-        args.http_ports, args.key
+        args.http_ports,
+        api_keys.default_key.as_deref().unwrap_or("<your key>")
     );
 
     // wait for all services to complete (it should never happen)
@@ -77,37 +124,54 @@ async fn main() -> Result<(), SharedError> {
 }
 
 async fn run_smtp_service(
-    tls_config: Arc<ServerConfig>,
+    settings: Arc<smtp::Settings>,
     db: Arc<Mutex<Db>>,
-    port: u16,
+    smtp_listener: SmtpListener,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let SmtpListener { port, tls_mode, chaos } = smtp_listener;
+
     // bind the TCP listener to the address
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-    println!("SMTP server running on port {}", port);
+    match tls_mode {
+        TlsMode::StartTls => println!("SMTP server running on port {}", port),
+        TlsMode::Implicit => println!("SMTPS server running on port {}", port),
+    }
+    if chaos != smtp::chaos::Chaos::default() {
+        println!("Injecting faults on port {}: {:?}", port, chaos);
+    }
+
+    // store mails as soon as a session completes them, a single connection
+    // can deliver any number of mails
+    let (mail_sender, mut mail_receiver) = mpsc::unbounded_channel::<smtp::mail::Mail>();
+    let storage_db = db.clone();
+    tokio::spawn(async move {
+        // the session only hands over mails with at least one recipient, and
+        // they were already accepted with a 250
+        while let Some(mail) = mail_receiver.recv().await {
+            let db = storage_db.lock().await;
+            let bytes = mail.to_bytes().unwrap();
+            db.insert(mail.id.to_le_bytes(), bytes).unwrap();
+            drop(db);
+
+            // wake up the requests waiting for this mail
+            events::publish(mail);
+        }
+    });
 
     loop {
         // accept a new incoming TCP connection
         let (socket, addr) = listener.accept().await?;
         println!("New client connected: {}", addr);
 
-        // clone the TLS configuration for the spawned task
-        let tls_config = tls_config.clone();
+        // clone the SMTP settings for the spawned task
+        let settings = settings.clone();
+        let mail_sender = mail_sender.clone();
         let db = db.clone();
 
         // spawn a new task to handle the client
         tokio::spawn(async move {
-            let result = smtp::handle_client(socket, tls_config, addr).await;
-            match result {
-                Ok(mail) => {
-                    if mail.from.len() > 0 && mail.to.len() > 0 && mail.data.len() > 20 {
-                        let db = db.lock().await;
-                        let bytes = bincode::serialize(&mail).unwrap();
-                        db.insert(mail.id.to_le_bytes(), bytes).unwrap();
-                    }
-                }
-                Err(e) => {
-                    println!("Error handling client {}: {:?}", addr, e);
-                }
+            if let Err(e) = smtp::handle_client(socket, settings, tls_mode, chaos, addr, mail_sender, db).await {
+                println!("Error handling client {}: {:?}", addr, e);
             }
         });
     }
@@ -116,7 +180,8 @@ async fn run_smtp_service(
 async fn run_http_service(
     db: Arc<Mutex<Db>>,
     i: u16,
-    key: String,
+    keys: Arc<http::auth::ApiKeys>,
+    tenants: Arc<tenant::Tenants>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     // bind the TCP listener to the address
     let listener = TcpListener::bind(format!("0.0.0.0:{}", i)).await?;
@@ -129,9 +194,10 @@ async fn run_http_service(
 
         // handle the connection (implement your service logic here)
         let db = db.clone();
-        let key = key.clone();
+        let keys = keys.clone();
+        let tenants = tenants.clone();
         tokio::spawn(async move {
-            if let Err(e) = http::handle_client(socket, db, key.as_str()).await {
+            if let Err(e) = http::handle_client(socket, db, &keys, tenants).await {
                 println!("Error handling client {}: {:?}", addr, e);
             }
         });
@@ -140,36 +206,50 @@ async fn run_http_service(
 
 async fn run_cleaner_service(
     db: Arc<Mutex<Db>>,
-    lifetime: u16,
+    lifetime: Option<u16>,
+    greylist: Option<smtp::greylist::Greylist>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     loop {
-        let db = db.clone();
-        let db = db.lock().await;
-        let mut keys = Vec::new();
-        for key in db.iter() {
-            keys.push(key.unwrap().0);
+        if let Some(lifetime) = lifetime {
+            match clean_expired_mails(&db, lifetime).await {
+                Ok(0) => {}
+                Ok(count) => println!("Cleaned {} emails", count),
+                Err(e) => println!("Error cleaning emails: {:?}", e),
+            }
         }
-
-        let mut count = 0;
-        for key in keys {
-            let mail = db.get(&key).unwrap().unwrap();
-            let mail: smtp::mail::Mail = bincode::deserialize(&mail).unwrap();
-            let current_millis = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-
-            if current_millis - mail.timestamp() > (lifetime as u128 * 60 * 1000) {
-                db.remove(&key).unwrap();
-                count += 1;
+        if let Some(greylist) = greylist {
+            match greylist.prune(&db).await {
+                Ok(0) => {}
+                Ok(count) => println!("Cleaned {} greylist triplets", count),
+                Err(e) => println!("Error cleaning the greylist: {:?}", e),
             }
         }
+        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+    }
+}
 
-        drop(db);
-
-        if count > 0 {
-            println!("Cleaned {} emails", count);
+async fn clean_expired_mails(db: &Mutex<Db>, lifetime: u16) -> Result<usize, SharedError> {
+    let db = db.lock().await;
+    let current_millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    // the keys are the snowflake ids, the mails don't need to be read so the
+    // ones that can't be are cleaned too
+    let mut expired = Vec::new();
+    for key in db.iter().keys() {
+        let key = key?;
+        let Ok(id) = key.as_ref().try_into().map(u128::from_le_bytes) else {
+            continue;
+        };
+        if current_millis.saturating_sub(snowflake::to_timestamp(id)) > (lifetime as u128 * 60 * 1000) {
+            expired.push(key);
         }
-        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
     }
+
+    for key in &expired {
+        db.remove(key)?;
+    }
+    Ok(expired.len())
 }
@@ -1,244 +1,1001 @@
+pub(crate) mod address;
+pub(crate) mod auth;
+pub(crate) mod chaos;
+pub(crate) mod extract;
+pub(crate) mod greylist;
 pub(crate) mod mail;
-
-use crate::smtp::mail::{get_data_from_to, get_subject, Mail};
+pub(crate) mod mime;
+pub(crate) mod rules;
+pub(crate) mod tls;
+
+use crate::smtp::auth::{AuthMode, Credentials};
+use crate::smtp::chaos::Chaos;
+use crate::smtp::greylist::Greylist;
+use crate::smtp::mail::{get_header_addresses, get_subject, Mail, SessionInfo};
+use crate::smtp::rules::{Rejection, Rule, Rules, Stage};
+use crate::tenant::Tenants;
 use crate::SharedError;
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use sled::Db;
 use std::collections::HashSet;
-use std::error::Error;
-use std::fs::File;
-use std::io::BufReader as StdBufReader;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::Instant;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
 use tokio::net::TcpStream;
-use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::ServerConnection;
 use tokio_rustls::TlsAcceptor;
 
-pub(crate) async fn handle_client(
-    stream: TcpStream,
-    tls_config: Arc<ServerConfig>,
-    peer_addr: SocketAddr,
-) -> Result<Mail, SharedError> {
-    let (reader, writer) = stream.into_split();
+/// Reads are buffered so pipelined commands can be detected, writes are
+/// buffered so the replies to a pipelined group are sent together.
+type Stream<S> = BufReader<BufWriter<S>>;
+
+/// A reply code, its enhanced status code (RFC 3463) and its text.
+type Reply<'a> = (u16, &'a str, &'a str);
+
+/// The longest command line, CRLF included (RFC 5321 4.5.3.1.4).
+const MAX_COMMAND_LINE: usize = 512;
+/// The longest text line of DATA, CRLF included (RFC 5321 4.5.3.1.6).
+const MAX_TEXT_LINE: usize = 1000;
+/// The longest line of an AUTH exchange (RFC 4954 4).
+const MAX_AUTH_LINE: usize = 12288;
+
+/// How a listener secures its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMode {
+    /// plaintext, upgraded on demand with STARTTLS
+    StartTls,
+    /// TLS handshake right after connecting (SMTPS)
+    Implicit,
+}
 
-    let mut reader = BufReader::new(reader);
-    let mut writer = writer;
+/// ESMTP extensions that can be advertised in the EHLO reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+#[value(rename_all = "UPPER")]
+pub enum Extension {
+    /// RFC 1870, `--max-size` is enforced whether it is advertised or not
+    Size,
+    /// RFC 6152
+    #[value(name = "8BITMIME")]
+    EightBitMime,
+    /// RFC 2920
+    Pipelining,
+    /// RFC 6531
+    Smtputf8,
+    /// RFC 2034
+    EnhancedStatusCodes,
+    /// RFC 3030, BDAT
+    Chunking,
+    /// RFC 3030, only advertised along with CHUNKING
+    BinaryMime,
+}
 
-    // greeting
-    writer.write_all(b"220 mail-sink\r\n").await?;
+/// Settings shared by every SMTP session.
+pub struct Settings {
+    /// name used in the greeting and the EHLO reply
+    pub hostname: String,
+    /// `None` when TLS is disabled, STARTTLS is then not advertised
+    pub tls_config: Option<Arc<tls::ReloadableConfig>>,
+    pub auth_mode: AuthMode,
+    pub credentials: Credentials,
+    pub extensions: HashSet<Extension>,
+    /// maximum message size in bytes, 0 for no limit
+    pub max_size: usize,
+    /// scripted replies for matching senders and recipients
+    pub rules: Rules,
+    /// `None` when greylisting is disabled
+    pub greylist: Option<Greylist>,
+    /// the tenants the mails are tagged with
+    pub tenants: Arc<Tenants>,
+}
 
-    let mut from = HashSet::new();
-    let mut to = HashSet::new();
-    let mut body = String::new();
+/// Where the client currently is in the SMTP dialogue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// connected, waiting for HELO/EHLO
+    Connected,
+    /// HELO/EHLO received
+    Greeted,
+    /// MAIL FROM accepted
+    Mail,
+    /// at least one RCPT TO accepted
+    Rcpt,
+    /// reading the message content
+    Data,
+    /// BDAT chunks received, waiting for the LAST one
+    Chunking,
+}
 
-    loop {
-        let mut line = String::new();
+/// Why a session loop stopped.
+enum Outcome {
+    /// the client quit or closed the connection
+    Closed,
+    /// the client asked to upgrade the connection to TLS
+    StartTls,
+}
 
-        let bytes_read = reader.read_line(&mut line).await?;
-        if bytes_read == 0 {
-            // connection closed :((((
-            break;
-        }
+/// Protocol state of a single SMTP connection, shared between the plaintext
+/// and the TLS part of the connection.
+struct Session {
+    settings: Arc<Settings>,
+    /// faults injected by the listener
+    chaos: Chaos,
+    peer_addr: SocketAddr,
+    /// port of the listener the client connected to
+    local_port: u16,
+    state: State,
+    tls: bool,
+    /// negotiated TLS version and cipher suite
+    tls_protocol: Option<String>,
+    tls_cipher: Option<String>,
+    /// name given with HELO or EHLO
+    helo: Option<String>,
+    /// the client greeted with EHLO, extensions can be used
+    esmtp: bool,
+    /// user that successfully authenticated with AUTH
+    auth_user: Option<String>,
+    /// when the current transaction started with `MAIL FROM`
+    started: Option<Instant>,
+    /// the transaction was started with the SMTPUTF8 parameter
+    smtputf8: bool,
+    /// the transaction was started with BODY=BINARYMIME, DATA can't be used
+    binarymime: bool,
+    /// content received so far with BDAT
+    chunks: Vec<u8>,
+    from: HashSet<String>,
+    to: HashSet<String>,
+    mails: UnboundedSender<Mail>,
+    /// where the rejections of the rules are logged
+    db: Arc<Mutex<Db>>,
+}
 
-        let command = line.trim_end();
-        let command_upper = command.to_uppercase();
+impl Session {
+    fn new(
+        settings: Arc<Settings>,
+        chaos: Chaos,
+        peer_addr: SocketAddr,
+        local_port: u16,
+        mails: UnboundedSender<Mail>,
+        db: Arc<Mutex<Db>>,
+    ) -> Self {
+        Self {
+            settings,
+            chaos,
+            peer_addr,
+            local_port,
+            state: State::Connected,
+            tls: false,
+            tls_protocol: None,
+            tls_cipher: None,
+            helo: None,
+            esmtp: false,
+            auth_user: None,
+            started: None,
+            smtputf8: false,
+            binarymime: false,
+            chunks: Vec::new(),
+            from: HashSet::new(),
+            to: HashSet::new(),
+            mails,
+            db,
+        }
+    }
 
-        if command_upper.starts_with("EHLO") || command_upper.starts_with("HELO") {
-            writer.write_all(b"250-localhost\r\n").await?;
-            // STARTTLS capability
-            writer.write_all(b"250-STARTTLS\r\n").await?;
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper.starts_with("STARTTLS") {
-            writer.write_all(b"220 Ready to start TLS\r\n").await?;
-            writer.flush().await?;
+    /// Runs the command loop on `stream` until the client quits, disconnects
+    /// or requests STARTTLS.
+    async fn run<S>(&mut self, stream: &mut Stream<S>) -> Result<Outcome, SharedError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let mut line = Vec::new();
+
+            let bytes_read = read_line(stream, &mut line, MAX_COMMAND_LINE).await?;
+            if bytes_read == 0 {
+                // connection closed :((((
+                return Ok(Outcome::Closed);
+            }
+            // AUTH can carry an initial response longer than a command
+            let max = if line.get(..5).is_some_and(|verb| verb.eq_ignore_ascii_case(b"AUTH ")) {
+                MAX_AUTH_LINE
+            } else {
+                MAX_COMMAND_LINE
+            };
+            if max > MAX_COMMAND_LINE && is_cut(&line, MAX_COMMAND_LINE) {
+                read_line(stream, &mut line, max - MAX_COMMAND_LINE).await?;
+            }
+            if is_cut(&line, max) {
+                skip_line(stream).await?;
+                self.respond(stream, (500, "5.5.2", "Line too long")).await?;
+                continue;
+            }
 
-            // Reunite the read and write halves
-            let stream = reader.into_inner().reunite(writer)?;
-            // Upgrade to TLS
-            let acceptor = TlsAcceptor::from(tls_config.clone());
-            let tls_stream = acceptor.accept(stream).await?;
+            if self.chaos.should_drop() {
+                println!("Client {} dropped by chaos", self.peer_addr);
+                let _ = stream.shutdown().await;
+                return Ok(Outcome::Closed);
+            }
+            if !self.chaos.command_delay.is_zero() {
+                tokio::time::sleep(self.chaos.command_delay).await;
+            }
 
-            let tls_mail = handle_tls_client(tls_stream).await;
-            match tls_mail {
-                Ok(m) => {
-                    from = m.from;
-                    to = m.to;
-                    body = m.data;
+            let command = String::from_utf8_lossy(&line);
+            let command = command.trim_end();
+            let (verb, arg) = command.split_once(' ').unwrap_or((command, ""));
+
+            match verb.to_ascii_uppercase().as_str() {
+                "EHLO" => {
+                    self.reset();
+                    self.state = State::Greeted;
+                    self.esmtp = true;
+                    self.helo = Some(arg.trim().to_string()).filter(|helo| !helo.is_empty());
+                    self.ehlo(stream).await?;
+                }
+                "HELO" => {
+                    self.reset();
+                    self.state = State::Greeted;
+                    self.esmtp = false;
+                    self.helo = Some(arg.trim().to_string()).filter(|helo| !helo.is_empty());
+                    self.reply(stream, &format!("250 {}", self.settings.hostname)).await?;
+                }
+                "STARTTLS" if self.can_start_tls() => {
+                    self.respond(stream, (220, "2.0.0", "Ready to start TLS")).await?;
+                    stream.flush().await?;
+                    return Ok(Outcome::StartTls);
+                }
+                "AUTH" => {
+                    if self.settings.auth_mode == AuthMode::Off {
+                        self.respond(stream, (502, "5.5.1", "Command not implemented")).await?;
+                    } else if !self.can_auth() {
+                        self.respond(stream, (538, "5.7.11", "Encryption required for requested authentication mechanism")).await?;
+                    } else if self.state != State::Greeted || self.auth_user.is_some() {
+                        self.respond(stream, (503, "5.5.1", "Bad sequence of commands")).await?;
+                    } else {
+                        match self.authenticate(stream, arg).await? {
+                            Ok(user) => {
+                                println!("Client {} authenticated as {}", self.peer_addr, user);
+                                self.auth_user = Some(user);
+                                self.respond(stream, (235, "2.7.0", "Authentication successful")).await?;
+                            }
+                            Err(error) => self.respond(stream, error).await?,
+                        }
+                    }
                 }
-                Err(e) => {
-                    println!("Error handling TLS client {}: {:?}", peer_addr, e);
+                "MAIL" => {
+                    if self.state != State::Greeted {
+                        self.respond(stream, (503, "5.5.1", "Bad sequence of commands")).await?;
+                        continue;
+                    }
+                    let Some((address, params)) = strip_prefix_ignore_case(arg, "FROM:").map(split_path) else {
+                        self.respond(stream, (501, "5.5.4", "Syntax error in parameters")).await?;
+                        continue;
+                    };
+                    let mut response = self.mail_params(params).unwrap_or_else(|| {
+                        self.check_address(&address)
+                            .unwrap_or((250, "2.1.0", "Sender OK"))
+                    });
+                    let rule = if response.0 == 250 {
+                        self.check_rules(Stage::Mail, &[&address]).await?
+                    } else {
+                        None
+                    };
+                    if let Some(rule) = &rule {
+                        response = rule_reply(rule);
+                    }
+                    if response.0 == 250 {
+                        self.from.insert(address);
+                        self.state = State::Mail;
+                        self.started = Some(Instant::now());
+                    } else {
+                        self.smtputf8 = false;
+                        self.binarymime = false;
+                    }
+                    self.respond(stream, response).await?;
                 }
+                "RCPT" => {
+                    if self.state != State::Mail && self.state != State::Rcpt {
+                        self.respond(stream, (503, "5.5.1", "Bad sequence of commands")).await?;
+                        continue;
+                    }
+                    let Some((address, params)) = strip_prefix_ignore_case(arg, "TO:").map(split_path) else {
+                        self.respond(stream, (501, "5.5.4", "Syntax error in parameters")).await?;
+                        continue;
+                    };
+                    let mut response = if !params.trim().is_empty() {
+                        (555, "5.5.4", "RCPT TO parameters not recognized or not implemented")
+                    } else {
+                        self.check_address(&address)
+                            .unwrap_or((250, "2.1.5", "Recipient OK"))
+                    };
+                    let rule = if response.0 == 250 {
+                        self.check_rules(Stage::Rcpt, &[&address]).await?
+                    } else {
+                        None
+                    };
+                    if let Some(rule) = &rule {
+                        response = rule_reply(rule);
+                    }
+                    if response.0 == 250 && !self.check_greylist(&address).await? {
+                        response = (451, "4.7.1", "Greylisted, please try again later");
+                    }
+                    if response.0 == 250 {
+                        self.to.insert(address);
+                        self.state = State::Rcpt;
+                    }
+                    self.respond(stream, response).await?;
+                }
+                "DATA" => {
+                    if self.state != State::Rcpt {
+                        self.respond(stream, (503, "5.5.1", "Bad sequence of commands")).await?;
+                        continue;
+                    }
+                    if self.binarymime {
+                        self.respond(stream, (503, "5.5.1", "BDAT required for BINARYMIME")).await?;
+                        continue;
+                    }
+                    self.state = State::Data;
+                    self.reply(stream, "354 End data with <CR><LF>.<CR><LF>").await?;
+
+                    let content = read_data(stream, self.settings.max_size).await?;
+                    if content == DataContent::Closed {
+                        // the incomplete mail is discarded
+                        return Ok(Outcome::Closed);
+                    }
+
+                    if !self.chaos.data_delay.is_zero() {
+                        tokio::time::sleep(self.chaos.data_delay).await;
+                    }
+
+                    let DataContent::Message(data) = content else {
+                        self.reset();
+                        self.respond(stream, (552, "5.3.4", "Message size exceeds fixed maximum message size")).await?;
+                        continue;
+                    };
+
+                    if let Some(rule) = self.check_data_rules().await? {
+                        self.reset();
+                        self.respond(stream, rule_reply(&rule)).await?;
+                        continue;
+                    }
+
+                    self.deliver(data)?;
+                    self.respond(stream, (250, "2.0.0", "OK")).await?;
+                }
+                "BDAT" if self.esmtp && self.has_extension(Extension::Chunking) => {
+                    let (size, last) = match parse_bdat(arg) {
+                        Some(chunk) => chunk,
+                        None => {
+                            // without a valid size the chunk can't be skipped
+                            self.respond(stream, (501, "5.5.4", "Syntax error in parameters")).await?;
+                            stream.flush().await?;
+                            return Ok(Outcome::Closed);
+                        }
+                    };
+
+                    // a refused chunk is still read, to stay in sync with the
+                    // client, but never buffered as its size can be anything
+                    if self.state != State::Rcpt && self.state != State::Chunking {
+                        skip_chunk(stream, size).await?;
+                        self.respond(stream, (503, "5.5.1", "Bad sequence of commands")).await?;
+                        continue;
+                    }
+                    let max_size = self.settings.max_size;
+                    if max_size > 0 && size > max_size.saturating_sub(self.chunks.len()) {
+                        skip_chunk(stream, size).await?;
+                        self.reset();
+                        self.respond(stream, (552, "5.3.4", "Message size exceeds fixed maximum message size")).await?;
+                        continue;
+                    }
+                    self.state = State::Chunking;
+
+                    // the buffer grows with what the client actually sends
+                    let read = (&mut *stream).take(size as u64).read_to_end(&mut self.chunks).await?;
+                    if read < size {
+                        return Err(From::from("Connection closed in a BDAT chunk"));
+                    }
+
+                    if last && !self.chaos.data_delay.is_zero() {
+                        tokio::time::sleep(self.chaos.data_delay).await;
+                    }
+
+                    if !last {
+                        self.respond(stream, (250, "2.0.0", "Chunk received")).await?;
+                    } else if let Some(rule) = self.check_data_rules().await? {
+                        self.reset();
+                        self.respond(stream, rule_reply(&rule)).await?;
+                    } else {
+                        let data = std::mem::take(&mut self.chunks);
+                        self.deliver(data)?;
+                        self.respond(stream, (250, "2.0.0", "OK")).await?;
+                    }
+                }
+                "RSET" => {
+                    self.reset();
+                    self.respond(stream, (250, "2.0.0", "OK")).await?;
+                }
+                "NOOP" => self.respond(stream, (250, "2.0.0", "OK")).await?,
+                "QUIT" => {
+                    self.respond(stream, (221, "2.0.0", "Bye")).await?;
+                    // the client may already be gone, nothing left to report
+                    let _ = stream.shutdown().await;
+                    return Ok(Outcome::Closed);
+                }
+                _ => self.respond(stream, (502, "5.5.1", "Command not implemented")).await?,
             }
-            break;
-        } else if command_upper.starts_with("MAIL FROM") {
-            from.insert(
-                command[10..]
-                    .to_string()
-                    .replace("<", "")
-                    .replace(">", "")
-                    .trim()
-                    .to_string(),
-            );
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper.starts_with("RCPT TO") {
-            to.insert(
-                command[8..]
-                    .to_string()
-                    .replace("<", "")
-                    .replace(">", "")
-                    .trim()
-                    .to_string(),
-            );
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper == "DATA" {
-            writer
-                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
-                .await?;
-
-            // email data processing
-            let mut data = String::new();
-            loop {
-                line.clear();
-                let bytes_read = reader.read_line(&mut line).await?;
-                if bytes_read == 0 {
-                    // connection closed unexpectedly
-                    break;
-                }
-                let trimmed_line = line.trim_end();
-                if trimmed_line == "." {
-                    break;
-                }
-                data.push_str(&line);
+        }
+    }
+
+    /// Sends the EHLO reply with every available extension.
+    async fn ehlo<S>(&self, stream: &mut Stream<S>) -> Result<(), SharedError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut lines = vec![self.settings.hostname.clone()];
+
+        if self.has_extension(Extension::Size) {
+            match self.settings.max_size {
+                0 => lines.push("SIZE".to_string()),
+                max_size => lines.push(format!("SIZE {}", max_size)),
             }
+        }
+        if self.has_extension(Extension::EightBitMime) {
+            lines.push("8BITMIME".to_string());
+        }
+        if self.has_extension(Extension::Pipelining) {
+            lines.push("PIPELINING".to_string());
+        }
+        if self.has_extension(Extension::Smtputf8) {
+            lines.push("SMTPUTF8".to_string());
+        }
+        if self.has_extension(Extension::EnhancedStatusCodes) {
+            lines.push("ENHANCEDSTATUSCODES".to_string());
+        }
+        if self.has_extension(Extension::Chunking) {
+            lines.push("CHUNKING".to_string());
+            if self.has_extension(Extension::BinaryMime) {
+                lines.push("BINARYMIME".to_string());
+            }
+        }
+        if self.can_start_tls() {
+            lines.push("STARTTLS".to_string());
+        }
+        if self.can_auth() {
+            lines.push(format!("AUTH {}", auth::MECHANISMS));
+        }
 
-            let (f, t) = get_data_from_to(&data);
-            f.iter().for_each(|s| {
-                from.insert(s.clone());
-            });
-            t.iter().for_each(|s| {
-                to.insert(s.clone());
-            });
-
-            body = data.clone();
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper == "QUIT" {
-            // reunite the read and write halves
-            let mut stream = reader.into_inner().reunite(writer)?;
-            // close the connection
-            stream.shutdown().await?;
-            break;
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            let separator = if i == last { ' ' } else { '-' };
+            self.reply(stream, &format!("250{}{}", separator, line)).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `reply`, with its enhanced status code when ENHANCEDSTATUSCODES
+    /// is enabled.
+    async fn respond<S>(
+        &self,
+        stream: &mut Stream<S>,
+        (code, status, text): Reply<'_>,
+    ) -> Result<(), SharedError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.has_extension(Extension::EnhancedStatusCodes) {
+            self.reply(stream, &format!("{} {} {}", code, status, text)).await
         } else {
-            writer.write_all(b"502 Command not implemented\r\n").await?;
+            self.reply(stream, &format!("{} {}", code, text)).await
         }
     }
 
-    let subject = get_subject(&body);
-    Ok(Mail::new(from, to, body, subject))
-}
+    /// Sends a reply line. When the listener truncates replies, only part of
+    /// it may be sent before the connection is closed.
+    async fn reply<S>(&self, stream: &mut Stream<S>, line: &str) -> Result<(), SharedError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Some(at) = self.chaos.truncate_at(line) {
+            stream.write_all(&line.as_bytes()[..at]).await?;
+            let _ = stream.shutdown().await;
+            return Err(From::from("Reply truncated by chaos"));
+        }
+        reply(stream, line).await
+    }
 
-async fn handle_tls_client(
-    stream: tokio_rustls::server::TlsStream<TcpStream>,
-    //peer_addr: SocketAddr,
-) -> Result<Mail, Box<dyn Error>> {
-    let (read_half, write_half) = tokio::io::split(stream);
-    let mut reader = BufReader::new(read_half);
-    let mut writer = write_half;
+    fn has_extension(&self, extension: Extension) -> bool {
+        self.settings.extensions.contains(&extension)
+    }
 
-    let mut from = HashSet::new();
-    let mut to = HashSet::new();
-    let mut body = String::new();
+    fn has_binarymime(&self) -> bool {
+        self.has_extension(Extension::Chunking) && self.has_extension(Extension::BinaryMime)
+    }
 
-    loop {
-        let mut line = String::new();
+    /// Checks the `MAIL FROM` parameters, returns the error reply if one of
+    /// them is refused.
+    fn mail_params(&mut self, params: &str) -> Option<Reply<'static>> {
+        self.smtputf8 = false;
+        self.binarymime = false;
+
+        for param in params.split_whitespace() {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key.to_ascii_uppercase().as_str() {
+                "SIZE" if self.esmtp && self.has_extension(Extension::Size) => {
+                    let Ok(size) = value.parse::<usize>() else {
+                        return Some((501, "5.5.4", "Syntax error in SIZE parameter"));
+                    };
+                    if self.settings.max_size > 0 && size > self.settings.max_size {
+                        return Some((552, "5.3.4", "Message size exceeds fixed maximum message size"));
+                    }
+                }
+                "BODY" if self.esmtp => match value.to_ascii_uppercase().as_str() {
+                    "7BIT" => {}
+                    "8BITMIME" if self.has_extension(Extension::EightBitMime) => {}
+                    "BINARYMIME" if self.has_binarymime() => self.binarymime = true,
+                    _ => return Some((501, "5.5.4", "Syntax error in BODY parameter")),
+                },
+                "SMTPUTF8" if self.esmtp && self.has_extension(Extension::Smtputf8) => {
+                    self.smtputf8 = true;
+                }
+                // RFC 4954, the submitter's identity isn't checked
+                "AUTH" if self.esmtp && self.can_auth() => {}
+                _ => {
+                    return Some((555, "5.5.4", "MAIL FROM parameters not recognized or not implemented"))
+                }
+            }
+        }
 
-        let bytes_read = reader.read_line(&mut line).await?;
-        if bytes_read == 0 {
-            // connection closed :((((
-            break;
+        None
+    }
+
+    /// Refuses internationalized addresses outside of an SMTPUTF8 transaction.
+    fn check_address(&self, address: &str) -> Option<Reply<'static>> {
+        if !address.is_ascii() && !self.smtputf8 {
+            Some((553, "5.6.7", "Non-ASCII addresses require SMTPUTF8"))
+        } else {
+            None
+        }
+    }
+
+    fn can_start_tls(&self) -> bool {
+        !self.tls && self.settings.tls_config.is_some()
+    }
+
+    fn can_auth(&self) -> bool {
+        match self.settings.auth_mode {
+            AuthMode::Off => false,
+            AuthMode::Tls => self.tls,
+            AuthMode::Always => true,
         }
+    }
 
-        let command = line.trim_end();
-        let command_upper = command.to_uppercase();
-
-        if command_upper.starts_with("EHLO") || command_upper.starts_with("HELO") {
-            writer.write_all(b"250-localhost\r\n").await?;
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper.starts_with("MAIL FROM") {
-            from.insert(command[10..].to_string().replace("<", "").replace(">", ""));
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper.starts_with("RCPT TO") {
-            to.insert(command[8..].to_string().replace("<", "").replace(">", ""));
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper == "DATA" {
-            writer
-                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
-                .await?;
-
-            // email data processing
-            let mut data = String::new();
-            loop {
-                line.clear();
-                let bytes_read = reader.read_line(&mut line).await?;
-                if bytes_read == 0 {
-                    // connection closed unexpectedly
-                    break;
-                }
-                let trimmed_line = line.trim_end();
-                if trimmed_line == "." {
-                    break;
-                }
-                data.push_str(&line);
+    /// Runs the AUTH exchange, returns the authenticated user or the error
+    /// reply to send.
+    async fn authenticate<S>(
+        &self,
+        stream: &mut Stream<S>,
+        arg: &str,
+    ) -> Result<Result<String, Reply<'static>>, SharedError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        const INVALID: Reply<'static> = (535, "5.7.8", "Authentication credentials invalid");
+        const MALFORMED: Reply<'static> = (501, "5.5.2", "Malformed authentication data");
+
+        let (mechanism, initial_response) = arg.split_once(' ').unwrap_or((arg, ""));
+        let credentials = &self.settings.credentials;
+
+        match mechanism.to_ascii_uppercase().as_str() {
+            "PLAIN" => {
+                let response = if initial_response.is_empty() {
+                    match auth_challenge(stream, "").await? {
+                        Ok(response) => response,
+                        Err(reply) => return Ok(Err(reply)),
+                    }
+                } else {
+                    initial_response.to_string()
+                };
+                let Some((user, password)) = auth::decode_plain(&response) else {
+                    return Ok(Err(MALFORMED));
+                };
+                if credentials.check_password(&user, &password) {
+                    Ok(Ok(user))
+                } else {
+                    Ok(Err(INVALID))
+                }
+            }
+            "LOGIN" => {
+                let user = if initial_response.is_empty() {
+                    match auth_challenge(stream, &auth::encode("Username:")).await? {
+                        Ok(response) => response,
+                        Err(reply) => return Ok(Err(reply)),
+                    }
+                } else {
+                    initial_response.to_string()
+                };
+                let Some(user) = auth::decode(&user) else {
+                    return Ok(Err(MALFORMED));
+                };
+                let password = match auth_challenge(stream, &auth::encode("Password:")).await? {
+                    Ok(password) => password,
+                    Err(reply) => return Ok(Err(reply)),
+                };
+                let Some(password) = auth::decode(&password) else {
+                    return Ok(Err(MALFORMED));
+                };
+                if credentials.check_password(&user, &password) {
+                    Ok(Ok(user))
+                } else {
+                    Ok(Err(INVALID))
+                }
             }
+            "CRAM-MD5" => {
+                let challenge = auth::cram_md5_challenge(&self.settings.hostname);
+                let response = match auth_challenge(stream, &auth::encode(&challenge)).await? {
+                    Ok(response) => response,
+                    Err(reply) => return Ok(Err(reply)),
+                };
+                let Some((user, digest)) = auth::decode(&response)
+                    .and_then(|r| r.rsplit_once(' ').map(|(u, d)| (u.to_string(), d.to_string())))
+                else {
+                    return Ok(Err(MALFORMED));
+                };
+                if credentials.check_cram_md5(&user, &challenge, &digest) {
+                    Ok(Ok(user))
+                } else {
+                    Ok(Err(INVALID))
+                }
+            }
+            _ => Ok(Err((504, "5.5.4", "Unrecognized authentication type"))),
+        }
+    }
 
-            let (f, t) = get_data_from_to(&data);
-            f.iter().for_each(|s| {
-                from.insert(s.clone());
-            });
-            t.iter().for_each(|s| {
-                to.insert(s.clone());
-            });
+    /// Returns the first rule of `stage` matching one of `addresses`, the
+    /// rejection is logged before the rule's reply is sent.
+    async fn check_rules(&self, stage: Stage, addresses: &[&str]) -> Result<Option<Rule>, SharedError> {
+        let Some((rule, address)) = self.settings.rules.check(stage, addresses) else {
+            return Ok(None);
+        };
+
+        let from = self.from.iter().next().cloned().unwrap_or_default();
+        let rejection = Rejection::new(stage, self.peer_addr.to_string(), from, address.to_string(), rule);
+        println!(
+            "Client {} refused by rule `{}` on {}: {} {}",
+            self.peer_addr, rule.pattern, address, rule.code, rule.text
+        );
+        rejection.log(&self.db).await?;
+
+        Ok(Some(rule.clone()))
+    }
+
+    /// Checks the end of DATA rules against the sender and the recipients.
+    async fn check_data_rules(&self) -> Result<Option<Rule>, SharedError> {
+        let addresses = self.from.iter().chain(&self.to).map(String::as_str).collect::<Vec<_>>();
+        self.check_rules(Stage::Data, &addresses).await
+    }
+
+    /// Returns whether the client may send to `recipient` under greylisting.
+    async fn check_greylist(&self, recipient: &str) -> Result<bool, SharedError> {
+        let Some(greylist) = &self.settings.greylist else {
+            return Ok(true);
+        };
 
-            body = data.clone();
+        let from = self.from.iter().next().map(String::as_str).unwrap_or_default();
+        let passed = greylist.check(&self.db, self.peer_addr.ip(), from, recipient).await?;
+        if !passed {
+            println!("Client {} greylisted: {} -> {}", self.peer_addr, from, recipient);
+        }
+        Ok(passed)
+    }
+
+    /// Resets the session after a successful TLS handshake, the client has to
+    /// greet again and nothing learned before TLS is kept (RFC 3207).
+    fn start_tls(&mut self, connection: &ServerConnection) {
+        self.reset();
+        self.tls = true;
+        self.tls_protocol = connection.protocol_version().map(|v| format!("{:?}", v));
+        self.tls_cipher = connection
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()));
+        self.state = State::Connected;
+        self.helo = None;
+        self.esmtp = false;
+        self.auth_user = None;
+    }
 
-            writer.write_all(b"250 OK\r\n").await?;
-        } else if command_upper == "QUIT" {
+    /// Turns the current transaction into a `Mail` and hands it over for
+    /// storage, the connection can then start a new transaction.
+    fn deliver(&mut self, data: Vec<u8>) -> Result<(), SharedError> {
+        let headers = get_header_addresses(&data);
+        let envelope_from = std::mem::take(&mut self.from).into_iter().next().unwrap_or_default();
+        let envelope_to = std::mem::take(&mut self.to);
+
+        let subject = get_subject(&data);
+        let session = SessionInfo {
+            client_ip: Some(self.peer_addr.ip()),
+            client_port: self.peer_addr.port(),
+            listener_port: self.local_port,
+            helo: self.helo.clone(),
+            esmtp: self.esmtp,
+            tls: self.tls,
+            tls_protocol: self.tls_protocol.clone(),
+            tls_cipher: self.tls_cipher.clone(),
+            receive_duration_ms: self.started.map_or(0, |started| started.elapsed().as_millis()),
+        };
+        let mut mail = Mail::new(
+            envelope_from,
+            envelope_to,
+            headers,
+            data,
+            subject,
+            self.auth_user.clone(),
+            session,
+        );
+        mail.tenants = self.settings.tenants.tenants_of(&mail);
+        self.mails
+            .send(mail)
+            .map_err(|_| "Mail storage is gone")?;
+
+        self.reset();
+        Ok(())
+    }
+
+    /// Drops the current transaction, as RSET, HELO and EHLO do.
+    fn reset(&mut self) {
+        self.from.clear();
+        self.to.clear();
+        self.smtputf8 = false;
+        self.binarymime = false;
+        self.chunks.clear();
+        self.started = None;
+        if self.state != State::Connected {
+            self.state = State::Greeted;
+        }
+    }
+}
+
+/// What the client sent after DATA.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DataContent {
+    /// the message, with the dot-stuffing undone
+    Message(Vec<u8>),
+    /// the message went over the maximum size, it wasn't kept
+    TooBig,
+    /// the connection closed before the end of the data
+    Closed,
+}
+
+/// Reads the content of DATA up to the line with a single dot, `max_size` is
+/// 0 for no limit. Lines are read in pieces of at most `MAX_TEXT_LINE` bytes,
+/// so the size is checked while an overlong line comes in; such lines are
+/// kept when the message fits.
+pub(crate) async fn read_data<S>(stream: &mut Stream<S>, max_size: usize) -> Result<DataContent, SharedError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut too_big = false;
+    let mut data = Vec::new();
+    let mut line = Vec::new();
+    let mut line_start = true;
+    loop {
+        line.clear();
+        if read_line(stream, &mut line, MAX_TEXT_LINE).await? == 0 {
+            return Ok(DataContent::Closed);
+        }
+        let piece_start = line_start;
+        line_start = line.ends_with(b"\n");
+        if piece_start && (line == b".\r\n" || line == b".\n") {
             break;
+        }
+        // undo the dot-stuffing (RFC 5321 4.5.2)
+        let line = if piece_start {
+            line.strip_prefix(b".").unwrap_or(&line)
         } else {
-            writer.write_all(b"502 Command not implemented\r\n").await?;
+            &line[..]
+        };
+        if max_size > 0 && data.len() + line.len() > max_size {
+            // keep reading until the end of the data, but stop buffering it
+            too_big = true;
+            data.clear();
+        }
+        if !too_big {
+            data.extend_from_slice(line);
         }
     }
 
-    let subject = get_subject(&body);
-    Ok(Mail::new(from, to, body, subject))
+    Ok(if too_big {
+        DataContent::TooBig
+    } else {
+        DataContent::Message(data)
+    })
+}
+
+/// Reads and drops a refused BDAT chunk of `size` bytes.
+async fn skip_chunk<S>(stream: &mut Stream<S>, size: usize) -> Result<(), SharedError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::io::copy(&mut (&mut *stream).take(size as u64), &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
+/// Serves a single SMTP connection, every completed transaction is sent to
+/// `mails` as soon as its DATA is received.
+pub(crate) async fn handle_client(
+    stream: TcpStream,
+    settings: Arc<Settings>,
+    tls_mode: TlsMode,
+    chaos: Chaos,
+    peer_addr: SocketAddr,
+    mails: UnboundedSender<Mail>,
+    db: Arc<Mutex<Db>>,
+) -> Result<(), SharedError> {
+    let local_port = stream.local_addr()?.port();
+    let mut session = Session::new(settings.clone(), chaos, peer_addr, local_port, mails, db);
+    let greeting = format!("220 {} ESMTP mail-sink", settings.hostname);
+
+    if tls_mode == TlsMode::Implicit {
+        let tls_config = settings
+            .tls_config
+            .as_ref()
+            .ok_or("Implicit TLS listener without TLS configuration")?
+            .current();
+        let acceptor = TlsAcceptor::from(tls_config);
+        let tls_stream = acceptor.accept(stream).await?;
+        session.start_tls(tls_stream.get_ref().1);
+        let mut stream = BufReader::new(BufWriter::new(tls_stream));
+
+        // greeting, only once the handshake is done
+        tokio::time::sleep(chaos.greeting_delay).await;
+        session.reply(&mut stream, &greeting).await?;
+        session.run(&mut stream).await?;
+
+        println!("Client {} disconnected", session.peer_addr);
+        return Ok(());
+    }
+
+    let mut stream = BufReader::new(BufWriter::new(stream));
+
+    // greeting
+    tokio::time::sleep(chaos.greeting_delay).await;
+    session.reply(&mut stream, &greeting).await?;
+
+    if let Outcome::StartTls = session.run(&mut stream).await? {
+        // STARTTLS is only accepted when there is a TLS configuration
+        let tls_config = settings.tls_config.as_ref().unwrap().current();
+
+        // anything the client pipelined after STARTTLS is dropped with the
+        // plaintext buffer, as required by RFC 3207
+        let acceptor = TlsAcceptor::from(tls_config);
+        let tls_stream = acceptor.accept(stream.into_inner().into_inner()).await?;
+
+        session.start_tls(tls_stream.get_ref().1);
+        session
+            .run(&mut BufReader::new(BufWriter::new(tls_stream)))
+            .await?;
+    }
+
+    println!("Client {} disconnected", session.peer_addr);
+    Ok(())
 }
 
-pub fn load_tls_config() -> Result<ServerConfig, Box<dyn Error + Send + Sync>> {
-    // load the TLS certificate and private key files
-    let cert_file = &mut StdBufReader::new(File::open("cert.pem")?);
-    let key_file = &mut StdBufReader::new(File::open("key.pem")?);
+async fn reply<S>(stream: &mut Stream<S>, line: &str) -> Result<(), SharedError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    Ok(())
+}
 
-    // cert pem
-    let cert_chain = certs(cert_file)
-        .map_err(|_| "Failed to read certificate file")?
-        .into_iter()
-        .map(Certificate)
-        .collect();
+/// Reads a line of at most `max` bytes from the client, a longer one is cut
+/// and the rest of it stays unread. Pending replies are flushed first unless
+/// the client already pipelined more commands, so a pipelined group gets all
+/// its replies at once (RFC 2920).
+async fn read_line<S>(stream: &mut Stream<S>, line: &mut Vec<u8>, max: usize) -> Result<usize, SharedError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if stream.buffer().is_empty() {
+        stream.flush().await?;
+    }
+    Ok((&mut *stream).take(max as u64).read_until(b'\n', line).await?)
+}
 
-    // keys pem
-    let mut keys = pkcs8_private_keys(key_file)
-        .map_err(|_| "Failed to read key file")?
-        .into_iter()
-        .map(PrivateKey)
-        .collect::<Vec<_>>();
+/// Whether `read_line` cut the line at `max` bytes.
+fn is_cut(line: &[u8], max: usize) -> bool {
+    line.len() == max && !line.ends_with(b"\n")
+}
 
-    if keys.is_empty() {
-        return Err(From::from("No private keys found in key.pem"));
+/// Drops the rest of a cut line, without buffering it.
+async fn skip_line<S>(stream: &mut Stream<S>) -> Result<(), SharedError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let buffer = stream.fill_buf().await?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        match buffer.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                stream.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buffer.len();
+                stream.consume(len);
+            }
+        }
+    }
+}
+
+/// Sends a `334` challenge and reads the client's response, or the reply to
+/// send when the client cancels the exchange with `*` or sends a line too long.
+async fn auth_challenge<S>(
+    stream: &mut Stream<S>,
+    challenge: &str,
+) -> Result<Result<String, Reply<'static>>, SharedError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    reply(stream, &format!("334 {}", challenge)).await?;
+
+    let mut line = Vec::new();
+    if read_line(stream, &mut line, MAX_AUTH_LINE).await? == 0 {
+        return Err(From::from("Connection closed during AUTH"));
     }
+    if is_cut(&line, MAX_AUTH_LINE) {
+        skip_line(stream).await?;
+        return Ok(Err((500, "5.5.6", "Authentication Exchange line is too long")));
+    }
+
+    let line = String::from_utf8_lossy(&line);
+    let line = line.trim_end();
+    Ok(if line == "*" {
+        Err((501, "5.0.0", "Authentication cancelled"))
+    } else {
+        Ok(line.to_string())
+    })
+}
+
+fn rule_reply(rule: &Rule) -> Reply<'_> {
+    (rule.code, &rule.status, &rule.text)
+}
+
+/// Parses the `BDAT <size> [LAST]` argument.
+fn parse_bdat(arg: &str) -> Option<(usize, bool)> {
+    let mut parts = arg.split_whitespace();
+    let size = parts.next()?.parse::<usize>().ok()?;
+    let last = match parts.next() {
+        None => false,
+        Some(last) if last.eq_ignore_ascii_case("LAST") => true,
+        Some(_) => return None,
+    };
+    Some((size, last))
+}
 
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, keys.remove(0))?;
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    if head.eq_ignore_ascii_case(prefix) {
+        Some(&s[prefix.len()..])
+    } else {
+        None
+    }
+}
 
-    Ok(config)
+/// Splits a `MAIL FROM`/`RCPT TO` argument into the address, with or without
+/// the angle brackets, and the ESMTP parameters that follow it.
+fn split_path(path: &str) -> (String, &str) {
+    let path = path.trim();
+    match (path.find('<'), path.find('>')) {
+        (Some(start), Some(end)) if start < end => {
+            (path[start + 1..end].trim().to_string(), &path[end + 1..])
+        }
+        _ => {
+            let (address, params) = path.split_once(' ').unwrap_or((path, ""));
+            (address.to_string(), params)
+        }
+    }
 }
@@ -0,0 +1,199 @@
+use rfc2047_decoder::decode;
+use serde::Serialize;
+
+/// A mailbox of an address header, with its display name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Address {
+    pub name: Option<String>,
+    pub address: String,
+    /// name of the group the mailbox was listed in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// Parses an RFC 5322 address list, like the value of a `To` header.
+///
+/// Quoted display names, comments and groups are supported, encoded words
+/// are decoded and punycode domains are converted to Unicode. Entries that
+/// aren't addresses are skipped.
+pub fn parse_address_list(value: &str) -> Vec<Address> {
+    let mut addresses = Vec::new();
+    let mut group: Option<String> = None;
+    let mut current = String::new();
+
+    let mut in_quotes = false;
+    let mut in_angle = false;
+    let mut comment_depth = 0;
+
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let top_level = !in_quotes && comment_depth == 0 && !in_angle;
+        match c {
+            '\\' if in_quotes || comment_depth > 0 => {
+                current.push(c);
+                current.extend(chars.next());
+                continue;
+            }
+            '"' if comment_depth == 0 => in_quotes = !in_quotes,
+            '(' if !in_quotes => comment_depth += 1,
+            ')' if !in_quotes && comment_depth > 0 => comment_depth -= 1,
+            '<' if !in_quotes && comment_depth == 0 => in_angle = true,
+            '>' if !in_quotes && comment_depth == 0 => in_angle = false,
+            ':' if top_level && group.is_none() => {
+                // `display-name:` starts a group
+                group = Some(parse_phrase(&strip_comments(&current).0));
+                current.clear();
+                continue;
+            }
+            ';' | ',' if top_level => {
+                addresses.extend(parse_mailbox(&current, group.as_deref()));
+                current.clear();
+                if c == ';' {
+                    group = None;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    addresses.extend(parse_mailbox(&current, group.as_deref()));
+
+    addresses
+}
+
+/// Parses a `name <addr-spec>` or `addr-spec (comment)` mailbox.
+fn parse_mailbox(s: &str, group: Option<&str>) -> Option<Address> {
+    let (text, comments) = strip_comments(s);
+
+    let (name, address) = match find_unquoted(&text, '<') {
+        Some(start) => {
+            let end = text[start..].find('>').map_or(text.len(), |end| start + end);
+            let mut address = &text[start + 1..end];
+            // obsolete source route, `<@relay:user@domain>`
+            if address.trim_start().starts_with('@') {
+                address = address.split_once(':').map_or("", |(_, a)| a);
+            }
+            (parse_phrase(&text[..start]), address.to_string())
+        }
+        // legacy `user@domain (Name)` form
+        None => (
+            comments.first().map(|c| parse_phrase(c)).unwrap_or_default(),
+            text,
+        ),
+    };
+
+    Some(Address {
+        name: (!name.is_empty()).then_some(name),
+        address: parse_addr_spec(&address)?,
+        group: group.filter(|g| !g.is_empty()).map(str::to_string),
+    })
+}
+
+/// Normalizes an `addr-spec`, `None` when it has no local part or no domain.
+fn parse_addr_spec(s: &str) -> Option<String> {
+    // folding whitespace is allowed around the dots and the `@`, but is kept
+    // in a quoted local part
+    let mut in_quotes = false;
+    let s = s
+        .trim()
+        .chars()
+        .filter(|&c| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            in_quotes || !c.is_whitespace()
+        })
+        .collect::<String>();
+    let (local, domain) = s.rsplit_once('@')?;
+    if local.is_empty() || domain.is_empty() {
+        return None;
+    }
+
+    let domain = if domain.to_ascii_lowercase().split('.').any(|label| label.starts_with("xn--")) {
+        match idna::domain_to_unicode(domain) {
+            (unicode, Ok(())) => unicode,
+            _ => domain.to_string(),
+        }
+    } else {
+        domain.to_string()
+    };
+
+    Some(format!("{}@{}", local, domain))
+}
+
+/// Turns a display name into text: quotes and escapes are removed, encoded
+/// words are decoded and whitespace is collapsed.
+fn parse_phrase(s: &str) -> String {
+    let mut text = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {}
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    decode(&text).unwrap_or(text)
+}
+
+/// Removes the comments outside of quoted strings, returns the remaining text
+/// and the comments.
+fn strip_comments(s: &str) -> (String, Vec<String>) {
+    let mut text = String::new();
+    let mut comments = Vec::new();
+    let mut comment = String::new();
+    let mut in_quotes = false;
+    let mut depth = 0;
+
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let target = if depth > 0 { &mut comment } else { &mut text };
+        match c {
+            '\\' if in_quotes || depth > 0 => {
+                target.push(c);
+                target.extend(chars.next());
+            }
+            '"' if depth == 0 => {
+                in_quotes = !in_quotes;
+                text.push(c);
+            }
+            '(' if !in_quotes => {
+                if depth > 0 {
+                    comment.push(c);
+                }
+                depth += 1;
+            }
+            ')' if !in_quotes && depth > 0 => {
+                depth -= 1;
+                if depth > 0 {
+                    comment.push(c);
+                } else {
+                    comments.push(std::mem::take(&mut comment));
+                    // a comment separates tokens like whitespace
+                    text.push(' ');
+                }
+            }
+            c => target.push(c),
+        }
+    }
+
+    (text, comments)
+}
+
+fn find_unquoted(s: &str, needle: char) -> Option<usize> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            c if c == needle && !in_quotes => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
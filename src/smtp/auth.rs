@@ -0,0 +1,93 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use std::collections::HashMap;
+
+/// Mechanisms advertised in the EHLO reply.
+pub const MECHANISMS: &str = "PLAIN LOGIN CRAM-MD5";
+
+/// When AUTH is advertised and accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AuthMode {
+    /// never, AUTH is answered with 502
+    Off,
+    /// only once the connection is encrypted (STARTTLS or implicit TLS)
+    Tls,
+    /// on every connection, even plaintext ones
+    Always,
+}
+
+/// Allowed `user:password` pairs, any credentials are accepted when empty.
+#[derive(Debug, Default)]
+pub struct Credentials(HashMap<String, String>);
+
+impl Credentials {
+    pub fn parse(users: &[String]) -> Result<Self, String> {
+        users
+            .iter()
+            .map(|user| {
+                user.split_once(':')
+                    .map(|(user, password)| (user.to_string(), password.to_string()))
+                    .ok_or_else(|| format!("Wrong AUTH user, expected `user:password`: `{}`", user))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map(Self)
+    }
+
+    pub fn check_password(&self, user: &str, password: &str) -> bool {
+        self.0.is_empty() || self.0.get(user).is_some_and(|p| p == password)
+    }
+
+    /// Checks a CRAM-MD5 `digest` (lowercase hex) against the expected
+    /// HMAC-MD5 of `challenge` keyed with the user's password.
+    pub fn check_cram_md5(&self, user: &str, challenge: &str, digest: &str) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+        let Some(password) = self.0.get(user) else {
+            return false;
+        };
+
+        let mut mac = Hmac::<Md5>::new_from_slice(password.as_bytes()).unwrap();
+        mac.update(challenge.as_bytes());
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        expected.eq_ignore_ascii_case(digest)
+    }
+}
+
+pub fn encode(s: &str) -> String {
+    BASE64.encode(s)
+}
+
+pub fn decode(s: &str) -> Option<String> {
+    let bytes = BASE64.decode(s.trim()).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Decodes a PLAIN response (`authzid \0 authcid \0 password`) into the
+/// user and password.
+pub fn decode_plain(s: &str) -> Option<(String, String)> {
+    let decoded = decode(s)?;
+    let mut parts = decoded.split('\0');
+    let _authzid = parts.next()?;
+    let user = parts.next()?;
+    let password = parts.next()?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// A unique challenge in the `<unique@hostname>` form of RFC 2195.
+pub fn cram_md5_challenge(hostname: &str) -> String {
+    format!(
+        "<{}.{}@{}>",
+        crate::snowflake::next(),
+        std::process::id(),
+        hostname
+    )
+}
@@ -0,0 +1,74 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Faults injected on a listener to test how senders handle slow or broken
+/// servers, set with options on an `--smtp-port` entry like
+/// `2526:greeting-delay=5s:drop=0.1`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Chaos {
+    /// wait before sending the greeting
+    pub greeting_delay: Duration,
+    /// wait before replying to the end of the message
+    pub data_delay: Duration,
+    /// wait before replying to every command
+    pub command_delay: Duration,
+    /// probability to close the connection instead of replying to a command
+    pub drop: f64,
+    /// probability to send only part of a reply, then close the connection
+    pub truncate: f64,
+}
+
+impl Chaos {
+    /// Applies a `key=value` option, returns `false` for an unknown key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key.to_lowercase().as_str() {
+            "greeting-delay" => self.greeting_delay = parse_duration(value)?,
+            "data-delay" => self.data_delay = parse_duration(value)?,
+            "command-delay" => self.command_delay = parse_duration(value)?,
+            "drop" => self.drop = parse_probability(value)?,
+            "truncate" => self.truncate = parse_probability(value)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn should_drop(&self) -> bool {
+        self.drop > 0.0 && rand::thread_rng().gen_bool(self.drop)
+    }
+
+    /// Returns how many bytes of `reply` to send when it has to be truncated.
+    pub fn truncate_at(&self, reply: &str) -> Option<usize> {
+        if self.truncate > 0.0 && rand::thread_rng().gen_bool(self.truncate) {
+            let mut at = rand::thread_rng().gen_range(0..reply.len().max(1));
+            while !reply.is_char_boundary(at) {
+                at -= 1;
+            }
+            Some(at)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses `500`, `500ms`, `2s` or `1m`, plain numbers are milliseconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let err = || format!("Wrong duration: `{}`", s);
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value = value.parse::<u64>().map_err(|_| err())?;
+    match unit {
+        "" | "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value.checked_mul(60).ok_or_else(err)?)),
+        _ => Err(err()),
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|p| (0.0..=1.0).contains(p))
+        .ok_or_else(|| format!("Wrong probability, expected a number between 0 and 1: `{}`", s))
+}
@@ -0,0 +1,171 @@
+use crate::smtp::mime::MimeContent;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{RwLock, RwLockReadGuard};
+
+/// Patterns used when `--code-regex` isn't given. The first capture group is
+/// the code when there is one, the whole match otherwise.
+const DEFAULT_CODE_PATTERNS: [&str; 2] = [
+    // a code right after a keyword, like `Your verification code: A1B2C3`
+    r"\b(?i:code|otp|pin|passcode|password|token)\b\W{0,5}(?:(?i:is)\W{0,3})?\b([0-9A-Z]{4,8})\b",
+    // a standalone 6 digit number, like `123456 is your code`
+    r"(?:^|[^\d\-/.:,])(\d{6})(?:$|[^\d\-/.:,])",
+];
+
+/// Schemes of the links that are kept, the others could run scripts where
+/// they are shown.
+const LINK_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+lazy_static! {
+    static ref CODE_PATTERNS: RwLock<Vec<Regex>> = RwLock::new(
+        DEFAULT_CODE_PATTERNS
+            .iter()
+            .map(|p| Regex::new(p).unwrap())
+            .collect()
+    );
+    static ref ANCHOR: Regex = Regex::new(
+        r#"(?is)<a\s[^>]*?href\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))[^>]*>(.*?)</a\s*>"#
+    )
+    .unwrap();
+    static ref URL: Regex = Regex::new(r#"(?i)\bhttps?://[^\s<>"'()\[\]{}]+"#).unwrap();
+    static ref TAG: Regex = Regex::new(r"(?s)<[^>]*>").unwrap();
+    static ref INVISIBLE: Regex = Regex::new(r"(?is)<(style|script|head)\b.*?</(style|script|head)\s*>").unwrap();
+    static ref ENTITY: Regex = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+}
+
+/// A link found in a mail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Link {
+    pub url: String,
+    /// the text of the `<a>` element, `None` for links found in plain text
+    pub text: Option<String>,
+}
+
+/// Sets the code patterns from `--code-regex`, the defaults are used when
+/// `patterns` is empty.
+pub fn set_code_patterns(patterns: &[String]) -> Result<(), regex::Error> {
+    if !patterns.is_empty() {
+        *CODE_PATTERNS.write().unwrap() = patterns.iter().map(|p| Regex::new(p)).collect::<Result<Vec<_>, _>>()?;
+    }
+    Ok(())
+}
+
+fn code_patterns() -> RwLockReadGuard<'static, Vec<Regex>> {
+    CODE_PATTERNS.read().unwrap()
+}
+
+/// Every URL of the HTML and text bodies, with the anchor text for HTML links.
+pub fn extract_links(content: &MimeContent) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut seen = HashSet::new();
+
+    if let Some(html) = &content.html {
+        for captures in ANCHOR.captures_iter(html) {
+            let href = captures
+                .get(1)
+                .or_else(|| captures.get(2))
+                .or_else(|| captures.get(3))
+                .map_or("", |m| m.as_str());
+            let url = decode_entities(href.trim());
+            if !is_safe_link(&url) {
+                continue;
+            }
+            let text = html_to_text(captures.get(4).map_or("", |m| m.as_str()));
+            let link = Link {
+                url,
+                text: (!text.is_empty()).then_some(text),
+            };
+            if seen.insert((link.url.clone(), link.text.clone())) {
+                links.push(link);
+            }
+        }
+    }
+
+    // bare URLs, in the text body and in the HTML text outside of anchors
+    let mut texts = Vec::new();
+    if let Some(text) = &content.text {
+        texts.push(text.clone());
+    }
+    if let Some(html) = &content.html {
+        texts.push(html_to_text(&ANCHOR.replace_all(html, " ")));
+    }
+    let known = links.iter().map(|l| l.url.clone()).collect::<HashSet<_>>();
+    for text in texts {
+        for m in URL.find_iter(&text) {
+            let url = m.as_str().trim_end_matches(['.', ',', ';', ':', '!', '?']).to_string();
+            if !known.contains(&url) && seen.insert((url.clone(), None)) {
+                links.push(Link { url, text: None });
+            }
+        }
+    }
+
+    links
+}
+
+/// Likely one-time codes of the subject and the bodies, in order of appearance.
+pub fn extract_codes(subject: Option<&str>, content: &MimeContent) -> Vec<String> {
+    let mut texts = Vec::new();
+    texts.extend(subject.map(str::to_string));
+    texts.extend(content.text.clone());
+    texts.extend(content.html.as_deref().map(html_to_text));
+
+    let mut codes = Vec::new();
+    let mut seen = HashSet::new();
+    for text in &texts {
+        for pattern in code_patterns().iter() {
+            for captures in pattern.captures_iter(text) {
+                let Some(code) = captures.get(1).or_else(|| captures.get(0)) else {
+                    continue;
+                };
+                let code = code.as_str().to_string();
+                if seen.insert(code.clone()) {
+                    codes.push(code);
+                }
+            }
+        }
+    }
+    codes
+}
+
+/// Whether `url` is absolute with one of `LINK_SCHEMES`, anchors and relative
+/// links are useless outside of the mail.
+fn is_safe_link(url: &str) -> bool {
+    // parsed like browsers do, `java\tscript:` is `javascript:`
+    url::Url::parse(url).is_ok_and(|url| LINK_SCHEMES.contains(&url.scheme()))
+}
+
+/// Strips the tags of an HTML fragment and decodes its entities.
+fn html_to_text(html: &str) -> String {
+    let text = INVISIBLE.replace_all(html, " ");
+    let text = TAG.replace_all(&text, " ");
+    decode_entities(&text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn decode_entities(s: &str) -> String {
+    ENTITY
+        .replace_all(s, |captures: &regex::Captures| {
+            let entity = &captures[1];
+            let c = if let Some(hex) = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+            } else if let Some(decimal) = entity.strip_prefix('#') {
+                decimal.parse::<u32>().ok().and_then(char::from_u32)
+            } else {
+                match entity {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some(' '),
+                    _ => None,
+                }
+            };
+            c.map_or_else(|| captures[0].to_string(), String::from)
+        })
+        .to_string()
+}
@@ -0,0 +1,62 @@
+use crate::SharedError;
+use sled::Db;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// Name of the sled tree the triplets are kept in.
+pub const GREYLIST_TREE: &str = "greylist";
+
+/// How long a triplet is kept once it may deliver, it is greylisted again
+/// after that.
+pub const TRIPLET_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Refuses the first attempt of each (client IP, sender, recipient) triplet,
+/// retries are accepted once `delay` has passed since the first attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct Greylist {
+    pub delay: Duration,
+}
+
+impl Greylist {
+    /// Returns whether the triplet may deliver, new triplets are recorded.
+    pub async fn check(&self, db: &Mutex<Db>, ip: IpAddr, from: &str, to: &str) -> Result<bool, SharedError> {
+        let key = format!("{}\0{}\0{}", ip, from.to_lowercase(), to.to_lowercase());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+        let db = db.lock().await;
+        let tree = db.open_tree(GREYLIST_TREE)?;
+        let Some(first_seen) = tree.get(&key)? else {
+            tree.insert(key, &now.to_le_bytes())?;
+            return Ok(false);
+        };
+
+        let first_seen = u128::from_le_bytes(first_seen.as_ref().try_into()?);
+        Ok(now.saturating_sub(first_seen) >= self.delay.as_millis())
+    }
+
+    /// Removes the triplets first seen more than `delay` and
+    /// `TRIPLET_LIFETIME` ago, returns how many were removed.
+    pub async fn prune(&self, db: &Mutex<Db>) -> Result<usize, SharedError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        // any delay is accepted, a huge one must not panic the cleaner
+        let max_age = self.delay.saturating_add(TRIPLET_LIFETIME).as_millis();
+
+        let db = db.lock().await;
+        let tree = db.open_tree(GREYLIST_TREE)?;
+        let mut expired = Vec::new();
+        for result in tree.iter() {
+            let (key, first_seen) = result?;
+            // unreadable entries are dropped too
+            let first_seen = first_seen.as_ref().try_into().map(u128::from_le_bytes).unwrap_or(0);
+            if now.saturating_sub(first_seen) > max_age {
+                expired.push(key);
+            }
+        }
+
+        for key in &expired {
+            tree.remove(key)?;
+        }
+        Ok(expired.len())
+    }
+}
@@ -1,156 +1,355 @@
-use mailparse::parse_mail;
-use rfc2047_decoder::decode;
+use crate::smtp::address::{parse_address_list, Address};
+use crate::smtp::mime::{self, MimeContent};
+use crate::SharedError;
+use bincode::Options;
+use mailparse::parse_headers;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct Mail {
+    /// every sender address, from the envelope and the headers
     pub from: HashSet<String>,
+    /// every recipient address, from the envelope and the headers
     pub to: HashSet<String>,
+    /// `MAIL FROM` address, empty for the null sender `<>`
+    pub envelope_from: String,
+    /// `RCPT TO` addresses
+    pub envelope_to: HashSet<String>,
+    pub header_from: HashSet<String>,
+    pub header_to: HashSet<String>,
+    pub cc: HashSet<String>,
+    pub bcc: HashSet<String>,
+    pub reply_to: HashSet<String>,
+    pub sender: Option<String>,
     pub subject: Option<String>,
-    pub data: String,
+    /// the message as received, it may not be valid UTF-8
+    pub data: Vec<u8>,
     pub id: u128,
+    /// user the client authenticated as with SMTP AUTH
+    pub auth_user: Option<String>,
+    /// how the mail was delivered
+    pub session: SessionInfo,
+    /// every tenant of the recipients, empty when none matches
+    pub tenants: Vec<String>,
+}
+
+/// Prefix of the stored mails, followed by the version of their layout.
+const STORAGE_MAGIC: &[u8] = b"mail-sink";
+/// Bumped on every change to the fields of `Mail`, with a fallback in
+/// `Mail::from_bytes` for the previous layouts.
+const STORAGE_VERSION: u8 = 1;
+
+/// Layout of the mails stored before it was versioned.
+#[derive(Deserialize)]
+struct LegacyMail {
+    from: HashSet<String>,
+    to: HashSet<String>,
+    subject: Option<String>,
+    data: String,
+    id: u128,
+}
+
+impl From<LegacyMail> for Mail {
+    fn from(legacy: LegacyMail) -> Self {
+        let headers = get_header_addresses(legacy.data.as_bytes());
+        Self {
+            // the envelope wasn't kept apart from the headers
+            envelope_to: legacy.to.clone(),
+            from: legacy.from,
+            to: legacy.to,
+            header_from: headers.from,
+            header_to: headers.to,
+            cc: headers.cc,
+            bcc: headers.bcc,
+            reply_to: headers.reply_to,
+            sender: headers.sender,
+            subject: legacy.subject,
+            data: legacy.data.into_bytes(),
+            id: legacy.id,
+            ..Default::default()
+        }
+    }
+}
+
+/// Details of the SMTP session a mail was received in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub client_ip: Option<IpAddr>,
+    pub client_port: u16,
+    /// port of the listener the client connected to
+    pub listener_port: u16,
+    /// name given with HELO or EHLO
+    pub helo: Option<String>,
+    /// the client greeted with EHLO
+    pub esmtp: bool,
+    pub tls: bool,
+    /// negotiated TLS version, like `TLSv1_3`
+    pub tls_protocol: Option<String>,
+    /// negotiated cipher suite, like `TLS13_AES_256_GCM_SHA384`
+    pub tls_cipher: Option<String>,
+    /// time from `MAIL FROM` to the end of the message
+    pub receive_duration_ms: u128,
+}
+
+/// Addresses found in the headers of a message.
+#[derive(Debug, Default)]
+pub struct HeaderAddresses {
+    pub from: HashSet<String>,
+    pub to: HashSet<String>,
+    pub cc: HashSet<String>,
+    pub bcc: HashSet<String>,
+    pub reply_to: HashSet<String>,
+    pub sender: Option<String>,
+}
+
+/// An address field of a mail that can be filtered on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressField {
+    /// envelope and header senders
+    From,
+    /// envelope and header recipients
+    To,
+    EnvelopeFrom,
+    EnvelopeTo,
+    HeaderFrom,
+    HeaderTo,
+    Cc,
+    Bcc,
+    ReplyTo,
+    Sender,
+}
+
+impl FromStr for AddressField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "from" => Ok(Self::From),
+            "to" => Ok(Self::To),
+            "envelope_from" => Ok(Self::EnvelopeFrom),
+            "envelope_to" => Ok(Self::EnvelopeTo),
+            "header_from" => Ok(Self::HeaderFrom),
+            "header_to" => Ok(Self::HeaderTo),
+            "cc" => Ok(Self::Cc),
+            "bcc" => Ok(Self::Bcc),
+            "reply_to" => Ok(Self::ReplyTo),
+            "sender" => Ok(Self::Sender),
+            _ => Err(format!("Unknown address field: `{}`", s)),
+        }
+    }
 }
 
 impl Mail {
+    /// The bytes stored in the database.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SharedError> {
+        let mut bytes = STORAGE_MAGIC.to_vec();
+        bytes.push(STORAGE_VERSION);
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Reads a mail stored by `to_bytes`, or by a version that didn't version
+    /// the layout yet.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SharedError> {
+        // the same encoding as `bincode::deserialize`, but a legacy record
+        // has to be read entirely
+        let strict = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes();
+        match bytes.strip_prefix(STORAGE_MAGIC) {
+            Some([STORAGE_VERSION, mail @ ..]) => Ok(strict.deserialize(mail)?),
+            Some([version, ..]) => Err(From::from(format!("Unknown stored mail version {}", version))),
+            _ => Ok(strict.deserialize::<LegacyMail>(bytes)?.into()),
+        }
+    }
+
+    /// The HTML body, or the text body when there is no HTML.
     pub fn parse_body(&self) -> String {
-        let mail = match parse_mail(self.data.as_bytes()) {
-            Ok(parsed) => parsed,
+        match self.mime() {
+            Some(content) => content.html.or(content.text).unwrap_or_default(),
             // return raw body if parsing fails
-            Err(_) => {
-                let mut body = self.data.clone();
+            None => {
+                let mut body = String::from_utf8_lossy(&self.data).to_string();
                 // after the headers
                 if let Some(index) = body.find("\r\n\r\n") {
                     body = body[index + 4..].to_string();
                 }
-                return body;
+                body
             }
-        };
-
-        // check if the email is multipart
-        if mail.subparts.is_empty() {
-            // not multipart, return the body as is
-            mail.get_body().unwrap_or_else(|_| String::new())
-        } else {
-            // prioritize 'text/html' parts
-            for part in &mail.subparts {
-                let content_type = part.ctype.mimetype.to_lowercase();
-                if content_type == "text/html" {
-                    // Return the HTML part's body
-                    return part.get_body().unwrap_or_else(|_| String::new());
-                }
-            }
-            // no 'text/html' part found, return the first multipart's body
-            mail.subparts[0]
-                .get_body()
-                .unwrap_or_else(|_| String::new())
         }
     }
 
+    pub fn headers(&self) -> Vec<Header> {
+        get_headers(&self.data)
+    }
+
+    pub fn mime(&self) -> Option<MimeContent> {
+        mime::parse(&self.data)
+    }
+
     pub fn timestamp(&self) -> u128 {
         crate::snowflake::to_timestamp(self.id)
     }
 
+    /// Whether `field` holds `address`, compared case-insensitively.
+    pub fn has_address(&self, field: AddressField, address: &str) -> bool {
+        let matches = |a: &String| a.eq_ignore_ascii_case(address);
+        match field {
+            AddressField::From => self.from.iter().any(matches),
+            AddressField::To => self.to.iter().any(matches),
+            AddressField::EnvelopeFrom => matches(&self.envelope_from),
+            AddressField::EnvelopeTo => self.envelope_to.iter().any(matches),
+            AddressField::HeaderFrom => self.header_from.iter().any(matches),
+            AddressField::HeaderTo => self.header_to.iter().any(matches),
+            AddressField::Cc => self.cc.iter().any(matches),
+            AddressField::Bcc => self.bcc.iter().any(matches),
+            AddressField::ReplyTo => self.reply_to.iter().any(matches),
+            AddressField::Sender => self.sender.iter().any(matches),
+        }
+    }
+
     pub fn new(
-        from: HashSet<String>,
-        to: HashSet<String>,
-        data: String,
+        envelope_from: String,
+        envelope_to: HashSet<String>,
+        headers: HeaderAddresses,
+        data: Vec<u8>,
         subject: Option<String>,
+        auth_user: Option<String>,
+        session: SessionInfo,
     ) -> Self {
+        // the combined sets keep working for clients that don't care where an
+        // address comes from
+        let mut from: HashSet<String> = headers.from.iter().chain(&headers.sender).cloned().collect();
+        if !envelope_from.is_empty() {
+            from.insert(envelope_from.clone());
+        }
+        let to = envelope_to
+            .iter()
+            .chain(&headers.to)
+            .chain(&headers.cc)
+            .chain(&headers.bcc)
+            .cloned()
+            .collect();
+
         Self {
             from,
             to,
+            envelope_from,
+            envelope_to,
+            header_from: headers.from,
+            header_to: headers.to,
+            cc: headers.cc,
+            bcc: headers.bcc,
+            reply_to: headers.reply_to,
+            sender: headers.sender,
             subject,
             data,
             id: crate::snowflake::next(),
+            auth_user,
+            session,
+            tenants: Vec::new(),
         }
     }
 }
 
-pub fn get_subject(data: &str) -> Option<String> {
-    for line in data.lines() {
-        if line.to_lowercase().starts_with("subject:") {
-            let subject = line[8..].trim().to_string();
-            return Some(decode(&subject).unwrap_or(subject));
+/// The mail stored under `key`, `None` when it can't be read. The record is
+/// logged and skipped, one bad record must not break every listing.
+pub fn read_stored(key: &[u8], bytes: &[u8]) -> Option<Mail> {
+    match Mail::from_bytes(bytes) {
+        Ok(mail) => Some(mail),
+        Err(e) => {
+            let id = key.try_into().map(u128::from_le_bytes).unwrap_or_default();
+            println!("Skipping stored mail {} that can't be read: {}", id, e);
+            None
         }
     }
+}
 
-    None
+/// A header of a message, unfolded and decoded.
+#[derive(Debug, Clone, Serialize)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+    /// the mailboxes of address headers like `From` or `To`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<Address>>,
 }
 
-pub fn get_data_from_to(data: &str) -> (HashSet<String>, HashSet<String>) {
-    let mut from_set = HashSet::new();
-    let mut to_set = HashSet::new();
-
-    let mut current_header_name = String::new();
-    let mut current_header_value = String::new();
-
-    for line in data.lines() {
-        if line.starts_with(' ') || line.starts_with('\t') {
-            // continuation line
-            current_header_value.push(' ');
-            current_header_value.push_str(line.trim());
-        } else if let Some((name, value)) = parse_header_line(line) {
-            process_header(
-                &current_header_name,
-                &current_header_value,
-                &mut from_set,
-                &mut to_set,
-            );
-            current_header_name = name;
-            current_header_value = value.to_string();
-        }
-    }
+/// Headers whose value is an address list.
+const ADDRESS_HEADERS: [&str; 11] = [
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "reply-to",
+    "sender",
+    "resent-from",
+    "resent-to",
+    "resent-cc",
+    "resent-bcc",
+    "resent-sender",
+];
 
-    process_header(
-        &current_header_name,
-        &current_header_value,
-        &mut from_set,
-        &mut to_set,
-    );
+/// Every header of the message in order, RFC 2047 encoded words decoded.
+pub fn get_headers(data: &[u8]) -> Vec<Header> {
+    let Ok((headers, _)) = parse_headers(data) else {
+        return Vec::new();
+    };
 
-    (from_set, to_set)
+    headers
+        .iter()
+        .map(|header| {
+            let name = header.get_key();
+            let addresses = if ADDRESS_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                // parsed before decoding, a decoded display name may contain
+                // commas or quotes
+                let raw = String::from_utf8_lossy(header.get_value_raw()).replace(['\r', '\n'], "");
+                Some(parse_address_list(&raw))
+            } else {
+                None
+            };
+            Header {
+                name,
+                value: header.get_value(),
+                addresses,
+            }
+        })
+        .collect()
 }
 
-fn parse_header_line(line: &str) -> Option<(String, &str)> {
-    if let Some(index) = line.find(':') {
-        let name = line[..index].trim().to_string();
-        let value = line[index + 1..].trim();
-        Some((name, value))
-    } else {
-        None // malformed line
-    }
+pub fn get_subject(data: &[u8]) -> Option<String> {
+    get_headers(data)
+        .into_iter()
+        .find(|header| header.name.eq_ignore_ascii_case("subject"))
+        .map(|header| header.value)
 }
 
-fn process_header(
-    header_name: &str,
-    header_value: &str,
-    from_set: &mut HashSet<String>,
-    to_set: &mut HashSet<String>,
-) {
-    if header_name.eq_ignore_ascii_case("From") {
-        extract_emails(header_value, from_set);
-    } else if header_name.eq_ignore_ascii_case("To") {
-        extract_emails(header_value, to_set);
-    }
-}
+/// Collects the addresses of the `From`, `To`, `Cc`, `Bcc`, `Reply-To` and
+/// `Sender` headers.
+pub fn get_header_addresses(data: &[u8]) -> HeaderAddresses {
+    let mut addresses = HeaderAddresses::default();
 
-fn extract_emails(header_value: &str, email_set: &mut HashSet<String>) {
-    for email_str in header_value.split(',').map(str::trim) {
-        if let Some(email) = extract_email_address(email_str) {
-            email_set.insert(email.trim().to_string());
-        }
+    for header in get_headers(data) {
+        let Some(list) = header.addresses else {
+            continue;
+        };
+        let set = match header.name.to_ascii_lowercase().as_str() {
+            "from" => &mut addresses.from,
+            "to" => &mut addresses.to,
+            "cc" => &mut addresses.cc,
+            "bcc" => &mut addresses.bcc,
+            "reply-to" => &mut addresses.reply_to,
+            "sender" => {
+                addresses.sender = list.into_iter().next().map(|a| a.address);
+                continue;
+            }
+            _ => continue,
+        };
+        set.extend(list.into_iter().map(|a| a.address));
     }
-}
 
-fn extract_email_address(s: &str) -> Option<String> {
-    if let Some(start) = s.find('<') {
-        if let Some(end) = s.find('>') {
-            Some(s[start + 1..end].to_string())
-        } else {
-            None // malformed email
-        }
-    } else {
-        Some(s.to_string())
-    }
+    addresses
 }
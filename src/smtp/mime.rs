@@ -0,0 +1,127 @@
+use mailparse::{parse_mail, DispositionType, MailHeaderMap, ParsedMail};
+use serde::Serialize;
+
+/// The readable content of a message, collected from every MIME part.
+#[derive(Debug, Default, Serialize)]
+pub struct MimeContent {
+    /// the `text/plain` parts, joined
+    pub text: Option<String>,
+    /// the `text/html` parts, joined
+    pub html: Option<String>,
+    pub attachments: Vec<Attachment>,
+    /// structure of the message
+    pub tree: MimePart,
+}
+
+/// A file attached to a message, inline images included.
+#[derive(Debug, Serialize)]
+pub struct Attachment {
+    /// position in the attachment list, used by the API
+    pub index: usize,
+    pub filename: Option<String>,
+    pub content_type: String,
+    /// decoded size in bytes
+    pub size: usize,
+    /// `Content-ID` without the angle brackets
+    pub content_id: Option<String>,
+    /// `Content-Disposition: inline`
+    pub inline: bool,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+/// A node of the MIME tree.
+#[derive(Debug, Default, Serialize)]
+pub struct MimePart {
+    pub content_type: String,
+    pub filename: Option<String>,
+    /// decoded size in bytes, 0 for multipart nodes
+    pub size: usize,
+    pub parts: Vec<MimePart>,
+}
+
+/// Parses `data`, `None` when it isn't a valid message.
+pub fn parse(data: &[u8]) -> Option<MimeContent> {
+    let mail = parse_mail(data).ok()?;
+    let mut content = MimeContent::default();
+    content.tree = walk(&mail, &mut content);
+    Some(content)
+}
+
+fn walk(part: &ParsedMail, content: &mut MimeContent) -> MimePart {
+    let content_type = part.ctype.mimetype.to_lowercase();
+
+    if !part.subparts.is_empty() {
+        // nested multiparts (e.g. alternative inside mixed) are walked too
+        return MimePart {
+            content_type,
+            filename: None,
+            size: 0,
+            parts: part.subparts.iter().map(|p| walk(p, content)).collect(),
+        };
+    }
+
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .map(|name| unescape(name));
+    let is_text = content_type == "text/plain" || content_type == "text/html";
+    let is_attachment =
+        disposition.disposition == DispositionType::Attachment || filename.is_some() || !is_text;
+
+    let size;
+    if is_attachment {
+        let data = part.get_body_raw().unwrap_or_default();
+        size = data.len();
+        content.attachments.push(Attachment {
+            index: content.attachments.len(),
+            filename: filename.clone(),
+            content_type: content_type.clone(),
+            size,
+            content_id: part
+                .headers
+                .get_first_value("Content-ID")
+                .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_string()),
+            inline: disposition.disposition == DispositionType::Inline,
+            data,
+        });
+    } else {
+        let body = part.get_body().unwrap_or_default();
+        size = body.len();
+        let target = if content_type == "text/html" {
+            &mut content.html
+        } else {
+            &mut content.text
+        };
+        match target {
+            Some(existing) => {
+                existing.push('\n');
+                existing.push_str(&body);
+            }
+            None => *target = Some(body),
+        }
+    }
+
+    MimePart {
+        content_type,
+        filename,
+        size,
+        parts: Vec::new(),
+    }
+}
+
+/// Removes the backslashes of quoted pairs, which mailparse keeps in quoted
+/// parameter values.
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
@@ -0,0 +1,230 @@
+use crate::SharedError;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Mutex as StdMutex;
+use tokio::sync::Mutex;
+
+/// Name of the sled tree the rejections are logged in.
+pub const REJECTIONS_TREE: &str = "rejections";
+
+/// When a rule is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// `MAIL FROM`, matched against the sender
+    Mail,
+    /// `RCPT TO`, matched against the recipient
+    Rcpt,
+    /// end of DATA, matched against the sender and every recipient
+    Data,
+}
+
+/// A scripted SMTP reply, like `*@fail.test -> 550 5.1.1 No such user`.
+///
+/// The syntax is `[mail:|rcpt:|data:]PATTERN -> CODE [X.Y.Z] [TEXT] [on first attempt]`,
+/// rules are checked at `RCPT TO` unless prefixed. `*` in the pattern matches
+/// any characters, the match is case-insensitive. With `on first attempt`,
+/// only the first attempt for each address is refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub stage: Stage,
+    pub pattern: String,
+    pub code: u16,
+    pub status: String,
+    pub text: String,
+    pub first_attempt_only: bool,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Wrong rule, expected `[stage:]PATTERN -> CODE [X.Y.Z] [TEXT]`: `{}`", s);
+        let (pattern, reply) = s.split_once("->").ok_or_else(err)?;
+
+        let pattern = pattern.trim();
+        let (stage, pattern) = match pattern.split_once(':') {
+            Some((stage, pattern)) if stage.eq_ignore_ascii_case("mail") => (Stage::Mail, pattern),
+            Some((stage, pattern)) if stage.eq_ignore_ascii_case("rcpt") => (Stage::Rcpt, pattern),
+            Some((stage, pattern)) if stage.eq_ignore_ascii_case("data") => (Stage::Data, pattern),
+            _ => (Stage::Rcpt, pattern),
+        };
+
+        let mut reply = reply.trim();
+        let first_attempt_only = match reply.len().checked_sub("on first attempt".len()) {
+            Some(i) if reply.is_char_boundary(i) && reply[i..].eq_ignore_ascii_case("on first attempt") => {
+                reply = reply[..i].trim_end();
+                true
+            }
+            _ => false,
+        };
+
+        let (code, reply) = reply.split_once(' ').unwrap_or((reply, ""));
+        let code = code
+            .parse::<u16>()
+            .ok()
+            .filter(|code| (400..600).contains(code))
+            .ok_or_else(err)?;
+
+        let (status, text) = reply.trim().split_once(' ').unwrap_or((reply.trim(), ""));
+        let (status, text) = if is_enhanced_status(status) {
+            (status.to_string(), text.trim().to_string())
+        } else {
+            // no enhanced status code, derive a generic one from the class
+            (format!("{}.0.0", code / 100), reply.trim().to_string())
+        };
+        let text = if text.is_empty() {
+            "Refused by mail-sink rule".to_string()
+        } else {
+            text
+        };
+
+        Ok(Self {
+            stage,
+            pattern: pattern.trim().to_lowercase(),
+            code,
+            status,
+            text,
+            first_attempt_only,
+        })
+    }
+}
+
+/// The configured rules and the addresses they have already seen.
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// (rule index, address) pairs already refused once
+    attempts: StdMutex<HashSet<(usize, String)>>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules,
+            attempts: StdMutex::new(HashSet::new()),
+        }
+    }
+
+    /// Parses `--rule` values and the lines of `--rules-file`, empty lines and
+    /// lines starting with `#` are ignored.
+    pub fn parse(rules: &[String], file: Option<&std::path::Path>) -> Result<Self, SharedError> {
+        let mut lines = rules.to_vec();
+        if let Some(file) = file {
+            let content = std::fs::read_to_string(file)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+            lines.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+
+        let rules = lines
+            .iter()
+            .map(|line| line.parse::<Rule>())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(rules))
+    }
+
+    /// Returns the first rule of `stage` matching one of `addresses`, with the
+    /// matched address.
+    pub fn check<'a>(&self, stage: Stage, addresses: &[&'a str]) -> Option<(&Rule, &'a str)> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.stage != stage {
+                continue;
+            }
+            for &address in addresses {
+                if !glob_match(&rule.pattern, &address.to_lowercase()) {
+                    continue;
+                }
+                if rule.first_attempt_only {
+                    let key = (index, address.to_lowercase());
+                    if !self.attempts.lock().unwrap().insert(key) {
+                        // already refused once, let it through
+                        continue;
+                    }
+                }
+                return Some((rule, address));
+            }
+        }
+        None
+    }
+}
+
+/// A refused command, logged so tests can assert on it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rejection {
+    pub id: u128,
+    pub stage: Stage,
+    /// `ip:port` of the client
+    pub peer: String,
+    /// the envelope sender, empty when refused at `MAIL FROM`
+    pub from: String,
+    /// the address that matched the rule
+    pub address: String,
+    pub code: u16,
+    pub status: String,
+    pub text: String,
+}
+
+impl Rejection {
+    pub fn new(stage: Stage, peer: String, from: String, address: String, rule: &Rule) -> Self {
+        Self {
+            id: crate::snowflake::next(),
+            stage,
+            peer,
+            from,
+            address,
+            code: rule.code,
+            status: rule.status.clone(),
+            text: rule.text.clone(),
+        }
+    }
+
+    pub fn timestamp(&self) -> u128 {
+        crate::snowflake::to_timestamp(self.id)
+    }
+
+    pub async fn log(&self, db: &Mutex<Db>) -> Result<(), SharedError> {
+        let db = db.lock().await;
+        let tree = db.open_tree(REJECTIONS_TREE)?;
+        // big endian keys keep the tree in chronological order
+        tree.insert(self.id.to_be_bytes(), bincode::serialize(self)?)?;
+        Ok(())
+    }
+}
+
+fn is_enhanced_status(s: &str) -> bool {
+    let parts = s.split('.').collect::<Vec<_>>();
+    parts.len() == 3
+        && parts
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Case-sensitive glob match where `*` matches any run of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let parts = pattern.split('*').collect::<Vec<_>>();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || text.len() < first.len() + last.len() || !text.ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
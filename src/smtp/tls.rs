@@ -0,0 +1,188 @@
+use crate::SharedError;
+use rustls_pemfile::{read_all, Item};
+use std::fs::File;
+use std::io::BufReader as StdBufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+/// How often the certificate and key files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// TLS configuration that can be swapped while the listeners keep running,
+/// new handshakes use the latest loaded certificate.
+pub struct ReloadableConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl ReloadableConfig {
+    /// Loads the certificate and key, or generates a self-signed certificate
+    /// for `hostname` when both files are missing. The generated certificate
+    /// is only written to disk when `write_cert` is set.
+    pub fn load_or_generate(
+        cert_path: &Path,
+        key_path: &Path,
+        hostname: &str,
+        write_cert: bool,
+    ) -> Result<Self, SharedError> {
+        let config = if cert_path.exists() || key_path.exists() {
+            load_tls_config(cert_path, key_path)?
+        } else {
+            generate_self_signed(cert_path, key_path, hostname, write_cert)?
+        };
+
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            config: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Reads the certificate and key again, the current configuration is kept
+    /// if they can't be loaded.
+    pub fn reload(&self) -> Result<(), SharedError> {
+        let config = load_tls_config(&self.cert_path, &self.key_path)?;
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+/// Reloads `config` on SIGHUP or when the certificate or key file changes.
+pub async fn watch(config: Arc<ReloadableConfig>) -> Result<(), SharedError> {
+    let mut hangup = Hangup::new()?;
+    let mut last_modified = config.modified();
+
+    loop {
+        let signaled = tokio::select! {
+            _ = hangup.recv() => true,
+            _ = tokio::time::sleep(WATCH_INTERVAL) => false,
+        };
+
+        let modified = config.modified();
+        if !signaled && modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        match config.reload() {
+            Ok(()) => println!("TLS certificate reloaded"),
+            Err(e) => eprintln!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
+
+fn generate_self_signed(
+    cert_path: &Path,
+    key_path: &Path,
+    hostname: &str,
+    write_cert: bool,
+) -> Result<ServerConfig, SharedError> {
+    println!(
+        "No {} / {} found, generating a self-signed certificate for {}",
+        cert_path.display(),
+        key_path.display(),
+        hostname
+    );
+    let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()])?;
+
+    if write_cert {
+        std::fs::write(cert_path, cert.serialize_pem()?)?;
+        std::fs::write(key_path, cert.serialize_private_key_pem())?;
+        println!(
+            "Self-signed certificate written to {} / {}",
+            cert_path.display(),
+            key_path.display()
+        );
+    }
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(cert.serialize_der()?)],
+            PrivateKey(cert.serialize_private_key_der()),
+        )?;
+
+    Ok(config)
+}
+
+pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig, SharedError> {
+    // cert pem
+    let cert_chain = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if cert_chain.is_empty() {
+        return Err(format!("No certificate found in {}", cert_path.display()).into());
+    }
+
+    // keys pem, PKCS#1 (RSA), SEC1 (EC) and PKCS#8 are accepted
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::ECKey(der) | Item::PKCS8Key(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private keys found in {}", key_path.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)?;
+
+    Ok(config)
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, SharedError> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    read_all(&mut StdBufReader::new(file))
+        .map_err(|_| format!("Failed to read {}", path.display()).into())
+}
+
+/// SIGHUP listener, it never fires on platforms without signals.
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Result<Self, SharedError> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self(signal(SignalKind::hangup())?))
+    }
+
+    async fn recv(&mut self) {
+        if self.0.recv().await.is_none() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Result<Self, SharedError> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,95 @@
+use crate::smtp::mail::Mail;
+use crate::smtp::rules::glob_match;
+use std::str::FromStr;
+
+/// A team sharing the sink, like `ci:ci.test,*.ci.test,ci-*@sink.test`.
+///
+/// The syntax is `NAME:PATTERN[,PATTERN...]`. A pattern with an `@` is matched
+/// against the whole recipient address, so it can select a prefix, any other
+/// pattern against its domain. `*` matches any characters, the match is
+/// case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    pub name: String,
+    pub patterns: Vec<String>,
+}
+
+impl FromStr for Tenant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Wrong tenant, expected `NAME:PATTERN[,PATTERN...]`: `{}`", s);
+        let (name, patterns) = s.trim().split_once(':').ok_or_else(err)?;
+        let patterns = patterns
+            .split(',')
+            .map(|pattern| pattern.trim().to_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .collect::<Vec<_>>();
+        if name.trim().is_empty() || patterns.is_empty() {
+            return Err(err());
+        }
+
+        Ok(Self {
+            name: name.trim().to_string(),
+            patterns,
+        })
+    }
+}
+
+impl Tenant {
+    pub fn matches(&self, address: &str) -> bool {
+        let address = address.to_lowercase();
+        let domain = address.rsplit_once('@').map_or("", |(_, domain)| domain);
+        self.patterns.iter().any(|pattern| {
+            if pattern.contains('@') {
+                glob_match(pattern, &address)
+            } else {
+                glob_match(pattern, domain)
+            }
+        })
+    }
+}
+
+/// The configured tenants.
+#[derive(Debug, Default)]
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+}
+
+impl Tenants {
+    /// Parses `--tenant` values, a name can't be used twice.
+    pub fn parse(tenants: &[String]) -> Result<Self, String> {
+        let tenants = tenants
+            .iter()
+            .map(|tenant| tenant.parse::<Tenant>())
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, tenant) in tenants.iter().enumerate() {
+            if tenants[..i].iter().any(|other| other.name == tenant.name) {
+                return Err(format!("Tenant `{}` is defined twice", tenant.name));
+            }
+        }
+        Ok(Self { tenants })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tenants.iter().map(|tenant| tenant.name.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names().any(|tenant| tenant == name)
+    }
+
+    /// Every tenant matching one of the `RCPT TO` addresses, the headers are
+    /// ignored as any sender can write them.
+    pub fn tenants_of(&self, mail: &Mail) -> Vec<String> {
+        self.tenants
+            .iter()
+            .filter(|tenant| mail.envelope_to.iter().any(|to| tenant.matches(to)))
+            .map(|tenant| tenant.name.clone())
+            .collect()
+    }
+}
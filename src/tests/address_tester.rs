@@ -0,0 +1,78 @@
+use crate::smtp::address::*;
+
+fn addresses(value: &str) -> Vec<(Option<String>, String)> {
+    parse_address_list(value)
+        .into_iter()
+        .map(|a| (a.name, a.address))
+        .collect()
+}
+
+fn mailbox(name: Option<&str>, address: &str) -> (Option<String>, String) {
+    (name.map(str::to_string), address.to_string())
+}
+
+#[test]
+fn test_quoted_display_names() {
+    assert_eq!(
+        addresses(r#""Doe, John" <j@x.com>, jane@x.com, "Smith \"Jr\"" <smith@x.com>"#),
+        vec![
+            mailbox(Some("Doe, John"), "j@x.com"),
+            mailbox(None, "jane@x.com"),
+            mailbox(Some(r#"Smith "Jr""#), "smith@x.com"),
+        ]
+    );
+}
+
+#[test]
+fn test_comments() {
+    // RFC 5322 appendix A.5
+    assert_eq!(
+        addresses(r"Pete(A nice \) chap) <pete(his account)@silly.test(his host)>"),
+        vec![mailbox(Some("Pete"), "pete@silly.test")]
+    );
+    assert_eq!(
+        addresses("john@x.com (John Doe), (only a comment)"),
+        vec![mailbox(Some("John Doe"), "john@x.com")]
+    );
+}
+
+#[test]
+fn test_groups() {
+    // RFC 5322 appendix A.1.3
+    let list = parse_address_list(
+        "A Group:Ed Jones <c@a.test>,joe@where.test,John <jdoe@one.test>;, Mary Smith <mary@x.test>",
+    );
+    assert_eq!(list.len(), 4);
+    assert!(list[..3].iter().all(|a| a.group.as_deref() == Some("A Group")));
+    assert_eq!(list[1].address, "joe@where.test");
+    assert_eq!(list[3].group, None);
+    assert_eq!(list[3].name.as_deref(), Some("Mary Smith"));
+
+    assert!(parse_address_list("Undisclosed recipients:;").is_empty());
+}
+
+#[test]
+fn test_invalid_entries_are_skipped() {
+    assert_eq!(
+        addresses("not an address, <>, @x.com, ok@x.com"),
+        vec![mailbox(None, "ok@x.com")]
+    );
+}
+
+#[test]
+fn test_encoded_and_international() {
+    assert_eq!(
+        addresses("=?utf-8?q?J=C3=A9r=C3=B4me?= <j@x.test>, <user@xn--bcher-kva.example>"),
+        vec![
+            mailbox(Some("Jérôme"), "j@x.test"),
+            mailbox(None, "user@bücher.example"),
+        ]
+    );
+    assert_eq!(
+        addresses(r#""john doe"@x.test, <@relay.test:routed@x.test>"#),
+        vec![
+            mailbox(None, r#""john doe"@x.test"#),
+            mailbox(None, "routed@x.test"),
+        ]
+    );
+}
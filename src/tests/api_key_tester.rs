@@ -0,0 +1,41 @@
+use crate::http::auth::*;
+use crate::tenant::Tenants;
+use crate::tests::mail_to;
+
+#[test]
+fn test_scopes() {
+    let key = "ci:s3cret:read,delete".parse::<ApiKey>().unwrap();
+    assert_eq!(key.name, "ci");
+    assert!(key.allows(Scope::Read) && key.allows(Scope::Delete));
+    assert!(!key.allows(Scope::Admin));
+    assert!(ApiKey::admin("s3cret").allows(Scope::Delete));
+
+    assert!("ci:s3cret:write".parse::<ApiKey>().is_err());
+    assert!("ci:s3cret".parse::<ApiKey>().is_err());
+    assert!("ci::read".parse::<ApiKey>().is_err());
+}
+
+#[test]
+fn test_domains() {
+    let key = "team:s3cret:read:B.test,*.b.test".parse::<ApiKey>().unwrap();
+    assert!(key.can_see(&mail_to(&["x@b.test"])));
+    assert!(key.can_see(&mail_to(&["x@a.test", "y@qa.B.TEST"])));
+    assert!(!key.can_see(&mail_to(&["x@a.test"])));
+    assert!(!key.can_see(&mail_to(&["x@notb.test"])));
+
+    let unrestricted = "all:s3cret:read".parse::<ApiKey>().unwrap();
+    assert!(unrestricted.can_see(&mail_to(&["x@a.test"])));
+}
+
+#[test]
+fn test_default_key() {
+    let keys = ApiKeys::parse(&[], None, None, &Tenants::default()).unwrap();
+    assert_eq!(keys.default_key.as_deref(), Some(DEFAULT_KEY));
+    assert!(keys.find(DEFAULT_KEY).is_some());
+
+    // configured keys replace the default one
+    let keys = ApiKeys::parse(&["ci:s3cret:read".to_string()], None, None, &Tenants::default()).unwrap();
+    assert!(keys.default_key.is_none());
+    assert!(keys.find(DEFAULT_KEY).is_none());
+    assert_eq!(keys.find("s3cret").unwrap().name, "ci");
+}
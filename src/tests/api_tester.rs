@@ -0,0 +1,138 @@
+use crate::http::auth::ApiKeys;
+use crate::http::handle_client;
+use crate::smtp::mail::Mail;
+use crate::tenant::Tenants;
+use sled::Db;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+fn temporary_db() -> Arc<Mutex<Db>> {
+    Arc::new(Mutex::new(sled::Config::new().temporary(true).open().unwrap()))
+}
+
+// the answer to `method path` sent with `key`, the keys are the admin `k`, the
+// `ci` tenant key `c` and the `ci.test` domain key `d`
+async fn raw_request(db: &Arc<Mutex<Db>>, method: &str, path: &str, key: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let db = db.clone();
+    tokio::spawn(async move {
+        let tenants = Tenants::parse(&["ci:ci.test".to_string()]).unwrap();
+        let keys = ["ci:c:admin::ci".to_string(), "domain:d:admin:ci.test".to_string()];
+        let keys = ApiKeys::parse(&keys, None, Some("k"), &tenants).unwrap();
+        handle_client(server, db, &keys, Arc::new(tenants)).await
+    });
+
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: a\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+        method, path, key
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+// the status and the body of the answer
+async fn request(db: &Arc<Mutex<Db>>, method: &str, path: &str, key: &str) -> (u16, String) {
+    let response = raw_request(db, method, path, key).await;
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, body.to_string())
+}
+
+async fn get(db: &Arc<Mutex<Db>>, path: &str) -> (u16, String) {
+    request(db, "GET", path, "k").await
+}
+
+#[tokio::test]
+async fn test_wait_timeout() {
+    let db = temporary_db();
+    assert_eq!(get(&db, "/mails/to/x@y.test/wait?timeout=10ms").await.0, 408);
+    assert_eq!(get(&db, "/mails/to/x@y.test/wait?timeout=11m").await.0, 400);
+    assert_eq!(get(&db, "/mails/to/x@y.test/wait?timeout=18446744073709551615s").await.0, 400);
+}
+
+async fn store(db: &Arc<Mutex<Db>>, mail: Mail) {
+    db.lock().await.insert(mail.id.to_le_bytes(), mail.to_bytes().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn test_encoded_segments() {
+    let db = temporary_db();
+    let data = "Content-Type: multipart/related; boundary=b\r\n\r\n\
+        --b\r\nContent-Type: text/html\r\n\r\n<img src=\"cid:a/b@x\">\r\n\
+        --b\r\nContent-Type: image/png\r\nContent-ID: <a/b@x>\r\nContent-Transfer-Encoding: base64\r\n\r\naGVsbG8=\r\n\
+        --b--\r\n";
+    store(
+        &db,
+        Mail {
+            envelope_to: ["a/b@x.test".to_string()].into(),
+            to: ["a/b@x.test".to_string()].into(),
+            data: data.as_bytes().to_vec(),
+            id: 42,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // a `%2F` stays in its segment
+    assert_eq!(get(&db, "/mails/42/cid/a%2Fb%40x").await, (200, "hello".to_string()));
+    assert_eq!(get(&db, "/mails/42/cid/a/b@x").await.0, 404);
+    let (status, body) = get(&db, "/mails/to/a%2Fb@x.test").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("\"id\":42"));
+    assert_eq!(get(&db, "/%6Dails/42").await.0, 200);
+}
+
+#[tokio::test]
+async fn test_rejections_scope() {
+    let db = temporary_db();
+    assert_eq!(get(&db, "/rejections").await, (200, "[]".to_string()));
+    for key in ["c", "d"] {
+        assert_eq!(request(&db, "GET", "/rejections", key).await.0, 403);
+        assert_eq!(request(&db, "DELETE", "/rejections", key).await.0, 403);
+    }
+    assert_eq!(request(&db, "DELETE", "/rejections", "k").await.0, 200);
+}
+
+#[tokio::test]
+async fn test_served_parts() {
+    let db = temporary_db();
+    let data = "Content-Type: multipart/related; boundary=b\r\n\r\n\
+        --b\r\nContent-Type: text/html\r\n\r\n<img src=\"cid:png\">\r\n\
+        --b\r\nContent-Type: image/PNG\r\nContent-ID: <png>\r\n\r\npng\r\n\
+        --b\r\nContent-Type: image/svg+xml\r\nContent-ID: <svg>\r\n\r\n<svg onload=\"alert(1)\"/>\r\n\
+        --b\r\nContent-Type: text/html; name=page.html\r\nContent-ID: <html>\r\n\r\n<script></script>\r\n\
+        --b\r\nContent-Type: text/plain\r\nContent-Disposition: attachment; filename*=utf-8''a%22b%0D%0AX-A: 1.txt\r\n\r\nhi\r\n\
+        --b--\r\n";
+    store(
+        &db,
+        Mail {
+            data: data.as_bytes().to_vec(),
+            id: 42,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let png = raw_request(&db, "GET", "/mails/42/cid/png", "k").await;
+    assert!(png.contains("Content-Type: image/png\r\n"));
+    assert!(png.contains("X-Content-Type-Options: nosniff\r\n"));
+    // parts that can run scripts aren't served with their type
+    for cid in ["svg", "html"] {
+        let part = raw_request(&db, "GET", &format!("/mails/42/cid/{}", cid), "k").await;
+        assert!(part.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(part.contains("X-Content-Type-Options: nosniff\r\n"));
+    }
+
+    // downloads keep their type, with the name made safe for the header
+    let attachment = raw_request(&db, "GET", "/mails/42/attachments/3", "k").await;
+    assert!(attachment.contains("Content-Type: text/plain\r\n"));
+    assert!(attachment.contains("Content-Disposition: attachment; filename=\"a_b__X-A: 1.txt\"\r\n"));
+    assert!(!attachment.contains("\r\nX-A: 1"));
+    assert_eq!(get(&db, "/mails/42/attachments/4").await.0, 404);
+}
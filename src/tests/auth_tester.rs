@@ -0,0 +1,44 @@
+use crate::smtp::auth::*;
+
+#[test]
+fn test_cram_md5() {
+    // RFC 2195 section 2
+    let credentials = Credentials::parse(&["tim:tanstaaftanstaaf".to_string()]).unwrap();
+    let challenge = "<1896.697170952@postoffice.reston.mci.net>";
+    assert!(credentials.check_cram_md5("tim", challenge, "b913a602c7eda7a495b4e6e7334d3890"));
+    assert!(credentials.check_cram_md5("tim", challenge, "B913A602C7EDA7A495B4E6E7334D3890"));
+    assert!(!credentials.check_cram_md5("tim", challenge, "b913a602c7eda7a495b4e6e7334d3891"));
+    assert!(!credentials.check_cram_md5("tom", challenge, "b913a602c7eda7a495b4e6e7334d3890"));
+    let other = "<1897.697170952@postoffice.reston.mci.net>";
+    assert!(!credentials.check_cram_md5("tim", other, "b913a602c7eda7a495b4e6e7334d3890"));
+
+    assert!(cram_md5_challenge("sink.test").ends_with("@sink.test>"));
+    assert_ne!(cram_md5_challenge("sink.test"), cram_md5_challenge("sink.test"));
+}
+
+#[test]
+fn test_decode_plain() {
+    let plain = |s: &str| decode_plain(&encode(s));
+    assert_eq!(plain("\0tim\0s3cret"), Some(("tim".to_string(), "s3cret".to_string())));
+    // the authorization identity is ignored
+    assert_eq!(plain("admin\0tim\0s3cret"), Some(("tim".to_string(), "s3cret".to_string())));
+    assert_eq!(plain("\0tim\0"), Some(("tim".to_string(), String::new())));
+    assert_eq!(plain("tim\0s3cret"), None);
+    assert_eq!(decode_plain("not base64!"), None);
+    assert_eq!(decode("dGlt\r\n").as_deref(), Some("tim"));
+}
+
+#[test]
+fn test_credentials() {
+    // without --auth-user, anything goes
+    let any = Credentials::default();
+    assert!(any.check_password("tim", "whatever"));
+    assert!(any.check_cram_md5("tim", "<1@sink.test>", "00"));
+
+    let credentials = Credentials::parse(&["tim:s3cret".to_string(), "tom:pass:word".to_string()]).unwrap();
+    assert!(credentials.check_password("tim", "s3cret"));
+    assert!(credentials.check_password("tom", "pass:word"));
+    assert!(!credentials.check_password("tim", "wrong"));
+    assert!(!credentials.check_password("nobody", "s3cret"));
+    assert!(Credentials::parse(&["tim".to_string()]).is_err());
+}
@@ -0,0 +1,16 @@
+use crate::smtp::chaos::parse_duration;
+use std::time::Duration;
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("500"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_duration(" 2s "), Ok(Duration::from_secs(2)));
+    assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("1h").is_err());
+    assert!(parse_duration("-1s").is_err());
+    assert!(parse_duration("18446744073709551615m").is_err());
+    assert!(parse_duration("18446744073709551616").is_err());
+}
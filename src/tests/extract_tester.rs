@@ -0,0 +1,54 @@
+use crate::smtp::extract::*;
+use crate::smtp::mime::MimeContent;
+
+fn content(text: Option<&str>, html: Option<&str>) -> MimeContent {
+    MimeContent {
+        text: text.map(str::to_string),
+        html: html.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+fn link(url: &str, text: Option<&str>) -> Link {
+    Link {
+        url: url.to_string(),
+        text: text.map(str::to_string),
+    }
+}
+
+#[test]
+fn test_links() {
+    let html = r##"<p>Hi <a class="x" href="https://a.test/verify?t=1&amp;u=2">Verify <b>now</b></a>,
+        <a href='mailto:help@a.test'>help</a> <a href=https://a.test/bare>bare</a>
+        <a href="#top">top</a> <a href="/relative">relative</a>
+        <a href="javascript:alert(1)">js</a> <a href=" java&#x09;script:alert(1)">tab</a>
+        <a href="data:text/html,<script>alert(1)</script>">data</a>
+        Or open https://a.test/plain. <a href="https://a.test/verify?t=1&amp;u=2">Verify <b>now</b></a></p>"##;
+    assert_eq!(
+        extract_links(&content(Some("See https://b.test/x, or javascript:alert(1)"), Some(html))),
+        vec![
+            link("https://a.test/verify?t=1&u=2", Some("Verify now")),
+            link("mailto:help@a.test", Some("help")),
+            link("https://a.test/bare", Some("bare")),
+            link("https://b.test/x", None),
+            link("https://a.test/plain", None),
+        ]
+    );
+    assert!(extract_links(&content(None, None)).is_empty());
+}
+
+#[test]
+fn test_codes() {
+    let codes = |subject: Option<&str>, text: &str| extract_codes(subject, &content(Some(text), None));
+    assert_eq!(codes(Some("Your code: A1B2C3"), ""), vec!["A1B2C3"]);
+    assert_eq!(codes(None, "123456 is your verification code"), vec!["123456"]);
+    assert_eq!(codes(None, "Your OTP is 4821"), vec!["4821"]);
+    // dates, phone numbers and amounts aren't codes
+    assert!(codes(None, "On 2024-10-18, call 555-123456 or pay 1,234567.00").is_empty());
+    assert!(codes(None, "Order 1234567 shipped").is_empty());
+    // each code once, in order of appearance
+    assert_eq!(codes(Some("Code 111111"), "111111 then 222222"), vec!["111111", "222222"]);
+
+    let html = content(None, Some("<style>.a{color:#123456}</style><p>Code: <b>654321</b></p>"));
+    assert_eq!(extract_codes(None, &html), vec!["654321"]);
+}
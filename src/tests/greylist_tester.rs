@@ -0,0 +1,33 @@
+use crate::smtp::greylist::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+#[tokio::test]
+async fn test_prune() {
+    let db = Mutex::new(sled::Config::new().temporary(true).open().unwrap());
+    let greylist = Greylist {
+        delay: Duration::from_secs(60),
+    };
+    let ip = "127.0.0.1".parse().unwrap();
+    assert!(!greylist.check(&db, ip, "a@b.test", "c@d.test").await.unwrap());
+
+    let old = SystemTime::now() - TRIPLET_LIFETIME - Duration::from_secs(120);
+    let old = old.duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let tree = db.lock().await.open_tree(GREYLIST_TREE).unwrap();
+    tree.insert("old", &old.to_le_bytes()).unwrap();
+
+    assert_eq!(greylist.prune(&db).await.unwrap(), 1);
+    assert!(tree.get("old").unwrap().is_none());
+    // the recent triplet is kept and still has to wait
+    assert_eq!(tree.len(), 1);
+    assert!(!greylist.check(&db, ip, "a@b.test", "c@d.test").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_huge_delay() {
+    let db = Mutex::new(sled::Config::new().temporary(true).open().unwrap());
+    let greylist = Greylist { delay: Duration::MAX };
+    let ip = "127.0.0.1".parse().unwrap();
+    assert!(!greylist.check(&db, ip, "a@b.test", "c@d.test").await.unwrap());
+    assert_eq!(greylist.prune(&db).await.unwrap(), 0);
+}
@@ -0,0 +1,176 @@
+use crate::http::protocol::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+async fn connect() -> (Reader, Writer, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let (reader, writer) = server.into_split();
+    (BufReader::new(reader), BufWriter::new(writer), client)
+}
+
+// the requests read from `input`, until the first error, and what the server
+// sent back while reading them
+async fn read(input: &[u8]) -> (Vec<Request>, Option<ReadError>, Vec<u8>) {
+    let (mut reader, mut writer, client) = connect().await;
+    let (mut client_reader, mut client_writer) = client.into_split();
+    // written aside, the server may stop reading before the end
+    let input = input.to_vec();
+    tokio::spawn(async move {
+        let _ = client_writer.write_all(&input).await;
+        let _ = client_writer.shutdown().await;
+    });
+
+    let mut requests = Vec::new();
+    let error = loop {
+        match read_request(&mut reader, &mut writer).await {
+            Ok(Some(request)) => requests.push(request),
+            Ok(None) => break None,
+            Err(e) => break Some(e),
+        }
+    };
+    drop(writer);
+
+    let mut output = Vec::new();
+    client_reader.read_to_end(&mut output).await.unwrap();
+    (requests, error, output)
+}
+
+async fn status(input: &[u8]) -> Option<u16> {
+    match read(input).await.1 {
+        Some(ReadError::Status(status)) => Some(status),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_pipelined_requests() {
+    let (requests, error, _) =
+        read(b"GET /mails/42?k=key&limit=5 HTTP/1.1\r\nHost: a\r\nX-A: 1\r\nx-a: 2\r\n\r\n\r\nGET /%6Dails HTTP/1.1\r\nHost: a\r\n\r\n").await;
+    assert!(error.is_none());
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].method, Method::GET);
+    assert_eq!(requests[0].path, "/mails/42");
+    assert_eq!(requests[0].query["limit"], "5");
+    assert_eq!(requests[0].headers["x-a"], "1, 2");
+    // decoded segment by segment by the router
+    assert_eq!(requests[1].path, "/%6Dails");
+}
+
+#[tokio::test]
+async fn test_bodies() {
+    let (requests, error, output) = read(
+        b"POST /a HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n\
+          5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n\
+          POST /b HTTP/1.1\r\nHost: a\r\nContent-Length: 3, 3\r\n\r\nabc",
+    )
+    .await;
+    assert!(error.is_none());
+    assert_eq!(requests[0].body, b"hello world");
+    assert_eq!(requests[1].body, b"abc");
+    assert_eq!(output, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+    // a body announced but not sent
+    let truncated = read(b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 16777216\r\n\r\nabc").await;
+    assert!(truncated.0.is_empty() && matches!(truncated.1, Some(ReadError::Io(_))));
+    let truncated = read(b"POST /a HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nffffff\r\nabc").await;
+    assert!(truncated.0.is_empty() && matches!(truncated.1, Some(ReadError::Io(_))));
+
+    // a length would be ambiguous
+    let both = b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+    assert_eq!(status(both).await, Some(400));
+    assert_eq!(status(b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 3, 4\r\n\r\nabcd").await, Some(400));
+    assert_eq!(status(b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: +3\r\n\r\nabc").await, Some(400));
+    assert_eq!(status(b"POST /a HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n").await, Some(501));
+    assert_eq!(status(b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999\r\n\r\n").await, Some(413));
+}
+
+#[tokio::test]
+async fn test_huge_chunks() {
+    let chunked = |size: &str| format!("POST /a HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\n", size);
+    assert_eq!(status(chunked("ffffffffffffffff").as_bytes()).await, Some(413));
+    assert_eq!(status(chunked("10000000000000000").as_bytes()).await, Some(413));
+    assert_eq!(status(chunked("1000001").as_bytes()).await, Some(413));
+    assert_eq!(status(chunked("+5").as_bytes()).await, Some(400));
+    assert_eq!(status(chunked("").as_bytes()).await, Some(400));
+
+    // the limit applies to the whole body
+    let mut input = chunked("800000").into_bytes();
+    input.extend(vec![b'a'; 0x800000]);
+    input.extend(b"\r\n800001\r\n");
+    assert_eq!(status(&input).await, Some(413));
+}
+
+#[tokio::test]
+async fn test_invalid_requests() {
+    let long_path = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(8 * 1024));
+    assert_eq!(status(long_path.as_bytes()).await, Some(414));
+    let long_header = format!("GET / HTTP/1.1\r\nHost: a\r\nX-A: {}\r\n\r\n", "a".repeat(8 * 1024));
+    assert_eq!(status(long_header.as_bytes()).await, Some(431));
+    let many_headers = format!("GET / HTTP/1.1\r\nHost: a\r\n{}\r\n", "X-A: 1\r\n".repeat(100));
+    assert_eq!(status(many_headers.as_bytes()).await, Some(431));
+
+    assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n").await, Some(400));
+    assert_eq!(status(b"GET / HTTP/1.0\r\n\r\n").await, None);
+    assert_eq!(status(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n").await, Some(400));
+    assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n").await, Some(400));
+    assert_eq!(status(b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n").await, Some(400));
+    assert_eq!(status(b"get / HTTP/1.1\r\nHost: a\r\n\r\n").await, Some(501));
+    assert_eq!(status(b"GET / HTTP/2.0\r\nHost: a\r\n\r\n").await, Some(505));
+    assert_eq!(status(b"GET * HTTP/1.1\r\nHost: a\r\n\r\n").await, Some(400));
+}
+
+#[tokio::test]
+async fn test_framing() {
+    let framing = |input: &'static [u8]| async move { read(input).await.0.remove(0).framing() };
+    let http10 = framing(b"GET / HTTP/1.0\r\n\r\n").await;
+    assert!(!http10.keep_alive && !http10.chunked);
+    assert!(framing(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").await.keep_alive);
+    assert!(framing(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.keep_alive);
+    assert!(!framing(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: x, close\r\n\r\n").await.keep_alive);
+    assert!(framing(b"HEAD / HTTP/1.1\r\nHost: a\r\n\r\n").await.head);
+}
+
+// what `response` looks like on the wire
+async fn written(response: Response, framing: Framing) -> String {
+    let (_, mut writer, mut client) = connect().await;
+    response.write(&mut writer, framing).await.unwrap();
+    drop(writer);
+    let mut output = String::new();
+    client.read_to_string(&mut output).await.unwrap();
+    output
+}
+
+#[tokio::test]
+async fn test_responses() {
+    let framing = Framing {
+        head: false,
+        chunked: true,
+        keep_alive: true,
+    };
+    let output = written(Response::with_body(200, "text/plain", "hello"), framing).await;
+    assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(output.contains("Content-Length: 5\r\n") && !output.contains("Connection: close"));
+    assert!(output.ends_with("\r\n\r\nhello"));
+
+    // the length of the GET response, without its body
+    let head = Framing { head: true, ..framing };
+    let output = written(Response::with_body(200, "text/plain", "hello"), head).await;
+    assert!(output.contains("Content-Length: 5\r\n") && output.ends_with("\r\n\r\n"));
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(2);
+    sender.send(b"ab".to_vec()).await.unwrap();
+    sender.send(Vec::new()).await.unwrap();
+    drop(sender);
+    let output = written(Response::stream("text/plain", receiver), framing).await;
+    assert!(output.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(output.ends_with("\r\n\r\n2\r\nab\r\n0\r\n\r\n"));
+
+    // HTTP/1.0 clients read a stream until the connection is closed
+    let (sender, receiver) = tokio::sync::mpsc::channel(1);
+    sender.send(b"ab".to_vec()).await.unwrap();
+    drop(sender);
+    let output = written(Response::stream("text/plain", receiver), Framing::close()).await;
+    assert!(output.contains("Connection: close\r\n") && output.ends_with("\r\n\r\nab"));
+}
@@ -0,0 +1,42 @@
+use crate::smtp::mime::*;
+
+#[test]
+fn test_nested_parts() {
+    let data = "Subject: hi\r\nContent-Type: multipart/mixed; boundary=outer\r\n\r\n\
+        --outer\r\nContent-Type: multipart/alternative; boundary=inner\r\n\r\n\
+        --inner\r\nContent-Type: text/plain\r\n\r\nhello\r\n\
+        --inner\r\nContent-Type: text/html\r\n\r\n<p>hello</p>\r\n\
+        --inner--\r\n\
+        --outer\r\nContent-Type: image/png\r\nContent-ID: <logo@x>\r\nContent-Disposition: inline\r\n\r\npng\r\n\
+        --outer\r\nContent-Type: text/plain\r\nContent-Disposition: attachment; filename=\"a\\\"b.txt\"\r\n\r\nnotes\r\n\
+        --outer\r\nContent-Type: text/csv\r\n\r\na,b\r\n\
+        --outer--\r\n";
+    let content = parse(data.as_bytes()).unwrap();
+
+    assert_eq!(content.text.as_deref(), Some("hello\r\n"));
+    assert_eq!(content.html.as_deref(), Some("<p>hello</p>\r\n"));
+
+    // every part that isn't a text body is an attachment
+    let attachments = &content.attachments;
+    assert_eq!(attachments.len(), 3);
+    assert_eq!(attachments[0].content_id.as_deref(), Some("logo@x"));
+    assert!(attachments[0].inline);
+    assert_eq!(attachments[0].data, b"png\r\n");
+    assert_eq!(attachments[1].filename.as_deref(), Some("a\"b.txt"));
+    assert!(!attachments[1].inline);
+    assert_eq!(attachments[2].content_type, "text/csv");
+    assert_eq!(attachments[2].index, 2);
+
+    assert_eq!(content.tree.content_type, "multipart/mixed");
+    assert_eq!(content.tree.parts.len(), 4);
+    assert_eq!(content.tree.parts[0].content_type, "multipart/alternative");
+    assert_eq!(content.tree.parts[0].parts.len(), 2);
+}
+
+#[test]
+fn test_single_part() {
+    let content = parse(b"Subject: hi\r\n\r\nhello\r\n").unwrap();
+    assert_eq!(content.text.as_deref(), Some("hello\r\n"));
+    assert!(content.html.is_none() && content.attachments.is_empty());
+    assert_eq!(content.tree.content_type, "text/plain");
+}
@@ -1 +1,41 @@
+#[cfg(test)]
+mod address_tester;
+#[cfg(test)]
+mod api_key_tester;
+#[cfg(test)]
+mod api_tester;
+#[cfg(test)]
+mod auth_tester;
+#[cfg(test)]
+mod chaos_tester;
+#[cfg(test)]
+mod extract_tester;
+#[cfg(test)]
+mod greylist_tester;
+#[cfg(test)]
+mod http_tester;
+#[cfg(test)]
+mod mime_tester;
+#[cfg(test)]
 mod parsing_tester;
+#[cfg(test)]
+mod rules_tester;
+#[cfg(test)]
+mod session_tester;
+#[cfg(test)]
+mod tenant_tester;
+#[cfg(test)]
+mod webhook_tester;
+#[cfg(test)]
+mod websocket_tester;
+
+/// A mail delivered to the `to` addresses, with a `To` header that never
+/// matches them as the headers must not change what a key sees.
+#[cfg(test)]
+fn mail_to(to: &[&str]) -> crate::smtp::mail::Mail {
+    crate::smtp::mail::Mail {
+        envelope_to: to.iter().map(|to| to.to_string()).collect(),
+        to: ["team@a.test".to_string()].into(),
+        ..Default::default()
+    }
+}
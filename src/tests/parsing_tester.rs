@@ -1,49 +1,115 @@
-#[cfg(test)]
-mod parsing_tester {
-    use crate::smtp::mail::*;
-
-    #[test]
-    fn test_parse_body_multipart() {
-        let body = std::fs::read_to_string("test/samples/discord_mail.body").unwrap();
-        let subject = get_subject(&body);
-        println!("subject: {:?}", subject);
-        let mail = Mail {
-            from: Default::default(),
-            to: Default::default(),
-            data: body,
-            subject,
-            id: 0,
-        };
-
-        let parsed = mail.parse_body();
-        assert!(parsed.starts_with("<!doctype html>"));
-
-        let (from, _) = get_data_from_to(&mail.data);
-        assert!(from.contains("noreply@discord.com"));
-
-        //should've decoded the subject with rfc2047 decoder
-        assert_eq!(mail.subject.unwrap(), "Vérifie ton adresse e-mail Discord");
-    }
+use crate::smtp::mail::*;
+use crate::smtp::{read_data, DataContent};
+use std::io::Cursor;
+use tokio::io::{BufReader, BufWriter};
+
+#[test]
+fn test_parse_body_multipart() {
+    let body = std::fs::read("test/samples/discord_mail.body").unwrap();
+    let subject = get_subject(&body);
+    println!("subject: {:?}", subject);
+    let mail = Mail {
+        from: Default::default(),
+        to: Default::default(),
+        data: body,
+        subject,
+        ..Default::default()
+    };
+
+    let parsed = mail.parse_body();
+    assert!(parsed.starts_with("<!doctype html>"));
+
+    let headers = get_header_addresses(&mail.data);
+    assert!(headers.from.contains("noreply@discord.com"));
+
+    //should've decoded the subject with rfc2047 decoder
+    assert_eq!(mail.subject.unwrap(), "Vérifie ton adresse e-mail Discord");
+}
+
+#[test]
+fn test_parse_body_simple() {
+    let body = std::fs::read("test/samples/raw.body").unwrap();
+    let subject = get_subject(&body);
+    let mail = Mail {
+        from: Default::default(),
+        to: Default::default(),
+        data: body,
+        subject,
+        ..Default::default()
+    };
+
+    let parsed = mail.parse_body();
+    assert_eq!(parsed.len(), 1809);
 
-    #[test]
-    fn test_parse_body_simple() {
-        let body = std::fs::read_to_string("test/samples/raw.body").unwrap();
-        let subject = get_subject(&body);
-        let mail = Mail {
-            from: Default::default(),
-            to: Default::default(),
-            data: body,
-            subject,
-            id: 0,
-        };
-
-        let parsed = mail.parse_body();
-        assert_eq!(parsed.len(), 1809);
-
-        let (from, to) = get_data_from_to(&mail.data);
-        assert!(from.contains("test@test.com"));
-        assert_eq!(to.len(), 8);
-
-        assert_eq!(mail.subject.unwrap(), "test smtp--");
+    let headers = get_header_addresses(&mail.data);
+    assert!(headers.from.contains("test@test.com"));
+    assert_eq!(headers.to.len(), 8);
+
+    assert_eq!(mail.subject.unwrap(), "test smtp--");
+}
+
+#[test]
+fn test_stored_layouts() {
+    let mail = Mail {
+        envelope_to: ["a@b.test".to_string()].into(),
+        data: b"Subject: hi\r\n\r\nhello".to_vec(),
+        id: 42,
+        tenants: vec!["ci".to_string(), "qa".to_string()],
+        ..Default::default()
+    };
+    let stored = Mail::from_bytes(&mail.to_bytes().unwrap()).unwrap();
+    assert_eq!((stored.id, stored.envelope_to, stored.tenants), (mail.id, mail.envelope_to, mail.tenants));
+
+    // mails stored before the layout was versioned
+    #[derive(serde::Serialize)]
+    struct LegacyMail {
+        from: std::collections::HashSet<String>,
+        to: std::collections::HashSet<String>,
+        subject: Option<String>,
+        data: String,
+        id: u128,
     }
+    let legacy = bincode::serialize(&LegacyMail {
+        from: ["x@y.test".to_string()].into(),
+        to: ["a@b.test".to_string()].into(),
+        subject: Some("hi".to_string()),
+        data: "From: x@y.test\r\nSubject: hi\r\n\r\nhello".to_string(),
+        id: 7,
+    })
+    .unwrap();
+    let stored = Mail::from_bytes(&legacy).unwrap();
+    assert_eq!(stored.id, 7);
+    assert!(stored.envelope_to.contains("a@b.test"));
+    assert!(stored.header_from.contains("x@y.test"));
+
+    assert!(Mail::from_bytes(b"garbage").is_err());
+    assert!(read_stored(&7u128.to_le_bytes(), b"garbage").is_none());
+}
+
+async fn data(input: &[u8], max_size: usize) -> DataContent {
+    let mut stream = BufReader::new(BufWriter::new(Cursor::new(input.to_vec())));
+    read_data(&mut stream, max_size).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_dot_stuffing() {
+    let message = |data: &[u8]| DataContent::Message(data.to_vec());
+    assert_eq!(data(b"a\r\n..leading\r\n.\r\n", 0).await, message(b"a\r\n.leading\r\n"));
+    assert_eq!(data(b"...\r\n.\r\n", 0).await, message(b"..\r\n"));
+    // only a line with a single dot ends the data
+    assert_eq!(data(b". \r\n.x\r\n.\r\nQUIT\r\n", 0).await, message(b" \r\nx\r\n"));
+    assert_eq!(data(b"a\n.\n", 0).await, message(b"a\n"));
+    assert_eq!(data(b".\r\n", 0).await, message(b""));
+
+    assert_eq!(data(b"a\r\n", 0).await, DataContent::Closed);
+    assert_eq!(data(b"a\r\n.", 0).await, DataContent::Closed);
+    assert_eq!(data(b"abcdef\r\n.\r\n", 4).await, DataContent::TooBig);
+
+    // long lines are read in pieces, a dot starting a piece isn't a line start
+    let long = [vec![b'a'; 1000], b".\r\n.\r\n".to_vec()].concat();
+    assert_eq!(data(&long, 0).await, message(&long[..1003]));
+    // an overlong line is kept when the message fits, and can't get past the limit
+    let huge = [vec![b'a'; 100_000], b"\r\n.\r\n".to_vec()].concat();
+    assert_eq!(data(&huge, 0).await, message(&huge[..100_002]));
+    assert_eq!(data(&huge, 50_000).await, DataContent::TooBig);
 }
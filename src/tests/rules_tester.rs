@@ -0,0 +1,74 @@
+use crate::smtp::rules::*;
+
+#[test]
+fn test_rule_syntax() {
+    // rule, stage, pattern, code, status, text, first attempt only
+    let cases = [
+        ("*@fail.test -> 550", Stage::Rcpt, "*@fail.test", 550, "5.0.0", "Refused by mail-sink rule", false),
+        ("*@fail.test -> 550 5.1.1 No such user", Stage::Rcpt, "*@fail.test", 550, "5.1.1", "No such user", false),
+        ("*@fail.test -> 550 No such user", Stage::Rcpt, "*@fail.test", 550, "5.0.0", "No such user", false),
+        ("MAIL:Spam@*  ->  421 4.7.0 Go away", Stage::Mail, "spam@*", 421, "4.7.0", "Go away", false),
+        ("rcpt:x@y.test -> 550", Stage::Rcpt, "x@y.test", 550, "5.0.0", "Refused by mail-sink rule", false),
+        ("data:* -> 554 5.6.0 Bad content", Stage::Data, "*", 554, "5.6.0", "Bad content", false),
+        ("*@grey.test -> 451 4.7.1 Try later on first attempt", Stage::Rcpt, "*@grey.test", 451, "4.7.1", "Try later", true),
+        ("*@grey.test -> 451 ON FIRST ATTEMPT", Stage::Rcpt, "*@grey.test", 451, "4.0.0", "Refused by mail-sink rule", true),
+    ];
+    for (rule, stage, pattern, code, status, text, first_attempt_only) in cases {
+        let parsed = rule.parse::<Rule>().unwrap_or_else(|e| panic!("{}: {}", rule, e));
+        assert_eq!(
+            parsed,
+            Rule {
+                stage,
+                pattern: pattern.to_string(),
+                code,
+                status: status.to_string(),
+                text: text.to_string(),
+                first_attempt_only,
+            },
+            "{}",
+            rule
+        );
+    }
+
+    for rule in ["*@fail.test", "*@fail.test -> ", "*@fail.test -> 250 OK", "*@fail.test -> 600", "x -> abc"] {
+        assert!(rule.parse::<Rule>().is_err(), "{}", rule);
+    }
+}
+
+#[test]
+fn test_first_attempt() {
+    let rules = Rules::parse(&["*@grey.test -> 451 on first attempt".to_string()], None).unwrap();
+    assert!(rules.check(Stage::Rcpt, &["a@grey.test"]).is_some());
+    assert!(rules.check(Stage::Rcpt, &["A@GREY.test"]).is_none());
+    assert!(rules.check(Stage::Rcpt, &["b@grey.test"]).is_some());
+    assert!(rules.check(Stage::Mail, &["c@grey.test"]).is_none());
+}
+
+#[test]
+fn test_glob_match() {
+    let cases = [
+        ("a@b.test", "a@b.test", true),
+        ("a@b.test", "a@b.testx", false),
+        ("*@b.test", "a@b.test", true),
+        ("*@b.test", "@b.test", true),
+        ("*@b.test", "a@c.test", false),
+        ("a@*", "a@b.test", true),
+        ("a@*", "ba@b.test", false),
+        ("*", "", true),
+        ("*", "anything", true),
+        ("**", "x", true),
+        ("", "", true),
+        ("", "a", false),
+        ("*@*.b.test", "a@x.b.test", true),
+        ("*@*.b.test", "a@b.test", false),
+        // the start and the end can't overlap
+        ("ab*ba", "aba", false),
+        ("a*b*c", "axxbyyc", true),
+        ("a*b*c", "acb", false),
+        // callers lowercase both sides
+        ("*@B.test", "a@b.test", false),
+    ];
+    for (pattern, text, expected) in cases {
+        assert_eq!(glob_match(pattern, text), expected, "{} ~ {}", pattern, text);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::smtp::auth::{AuthMode, Credentials};
+use crate::smtp::chaos::Chaos;
+use crate::smtp::mail::Mail;
+use crate::smtp::rules::Rules;
+use crate::smtp::{handle_client, Settings, TlsMode};
+use crate::tenant::Tenants;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::Mutex;
+
+struct Client {
+    stream: BufReader<TcpStream>,
+    mails: UnboundedReceiver<Mail>,
+}
+
+impl Client {
+    // a plaintext session without extensions nor limits, greeted already
+    async fn connect() -> Self {
+        let settings = Arc::new(Settings {
+            hostname: "sink.test".to_string(),
+            tls_config: None,
+            auth_mode: AuthMode::Off,
+            credentials: Credentials::default(),
+            extensions: HashSet::new(),
+            max_size: 0,
+            rules: Rules::default(),
+            greylist: None,
+            tenants: Arc::new(Tenants::default()),
+        });
+        let db = Arc::new(Mutex::new(sled::Config::new().temporary(true).open().unwrap()));
+        let (sender, mails) = mpsc::unbounded_channel();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, peer_addr) = listener.accept().await.unwrap();
+        tokio::spawn(handle_client(server, settings, TlsMode::StartTls, Chaos::default(), peer_addr, sender, db));
+
+        let mut client = Self {
+            stream: BufReader::new(stream),
+            mails,
+        };
+        assert_eq!(client.reply().await, 220);
+        client
+    }
+
+    async fn send(&mut self, data: &str) {
+        self.stream.get_mut().write_all(data.as_bytes()).await.unwrap();
+    }
+
+    // the code of the next reply, multiline ones included
+    async fn reply(&mut self) -> u16 {
+        loop {
+            let mut line = String::new();
+            self.stream.read_line(&mut line).await.unwrap();
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return line[..3].parse().unwrap();
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str) -> u16 {
+        self.send(&format!("{}\r\n", command)).await;
+        self.reply().await
+    }
+}
+
+#[tokio::test]
+async fn test_transactions() {
+    let mut client = Client::connect().await;
+    assert_eq!(client.command("EHLO client.test").await, 250);
+    assert_eq!(client.command("NOOP").await, 250);
+
+    for to in ["a@y.test", "b@y.test"] {
+        assert_eq!(client.command("MAIL FROM:<x@x.test>").await, 250);
+        assert_eq!(client.command(&format!("RCPT TO:<{}>", to)).await, 250);
+        assert_eq!(client.command("DATA").await, 354);
+        assert_eq!(client.command(&format!("Subject: {}\r\n\r\nhello\r\n.", to)).await, 250);
+    }
+
+    // RSET forgets the sender and the recipients
+    assert_eq!(client.command("MAIL FROM:<x@x.test>").await, 250);
+    assert_eq!(client.command("RCPT TO:<old@y.test>").await, 250);
+    assert_eq!(client.command("RSET").await, 250);
+    assert_eq!(client.command("RCPT TO:<new@y.test>").await, 503);
+    assert_eq!(client.command("DATA").await, 503);
+
+    // a pipelined group gets its replies in order
+    client
+        .send("MAIL FROM:<z@x.test>\r\nRCPT TO:<new@y.test>\r\nVRFY new\r\nDATA\r\n")
+        .await;
+    assert_eq!(client.reply().await, 250);
+    assert_eq!(client.reply().await, 250);
+    assert_eq!(client.reply().await, 502);
+    assert_eq!(client.reply().await, 354);
+    assert_eq!(client.command("pipelined\r\n.").await, 250);
+    assert_eq!(client.command("QUIT").await, 221);
+
+    let mut mails = Vec::new();
+    while let Some(mail) = client.mails.recv().await {
+        mails.push(mail);
+    }
+    assert_eq!(mails.len(), 3);
+    assert_eq!(mails[0].envelope_to, ["a@y.test".to_string()].into());
+    assert_eq!(mails[0].data, b"Subject: a@y.test\r\n\r\nhello\r\n");
+    assert_eq!(mails[1].envelope_to, ["b@y.test".to_string()].into());
+    assert_eq!(mails[2].envelope_from, "z@x.test");
+    assert_eq!(mails[2].envelope_to, ["new@y.test".to_string()].into());
+    assert_eq!(mails[2].data, b"pipelined\r\n");
+}
+
+#[tokio::test]
+async fn test_long_lines() {
+    let mut client = Client::connect().await;
+    assert_eq!(client.command(&format!("EHLO {}", "a".repeat(100_000))).await, 500);
+    // the rest of the line was dropped, the session goes on
+    assert_eq!(client.command(&format!("EHLO {}", "a".repeat(500))).await, 250);
+    assert_eq!(client.command("MAIL FROM:<x@x.test>").await, 250);
+    assert_eq!(client.command(&format!("RCPT TO:<{}@y.test>", "a".repeat(600))).await, 500);
+}
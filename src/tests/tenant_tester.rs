@@ -0,0 +1,72 @@
+use crate::http::auth::ApiKeys;
+use crate::smtp::mail::Mail;
+use crate::tenant::*;
+use crate::tests::mail_to;
+
+// a mail tagged with its tenants, like the SMTP sessions do
+fn tagged_mail(to: &[&str], tenants: &Tenants) -> Mail {
+    let mut mail = mail_to(to);
+    mail.tenants = tenants.tenants_of(&mail);
+    mail
+}
+
+#[test]
+fn test_tenants_of() {
+    let tenants = Tenants::parse(&[
+        "ci:ci.test,*.ci.test,ci-*@sink.test".to_string(),
+        "qa:qa.test,*@sink.test".to_string(),
+    ])
+    .unwrap();
+    let tenants_of = |to: &[&str]| tagged_mail(to, &tenants).tenants;
+    assert_eq!(tenants_of(&["x@CI.test"]), ["ci"]);
+    assert_eq!(tenants_of(&["x@eu.ci.test"]), ["ci"]);
+    assert_eq!(tenants_of(&["qa-42@sink.test"]), ["qa"]);
+    // every matching tenant, in the order of --tenant
+    assert_eq!(tenants_of(&["ci-42@sink.test"]), ["ci", "qa"]);
+    assert_eq!(tenants_of(&["x@qa.test", "x@ci.test"]), ["ci", "qa"]);
+    assert!(tenants_of(&["x@other.test"]).is_empty());
+
+    assert!(Tenants::parse(&["ci".to_string()]).is_err());
+    assert!(Tenants::parse(&["ci:".to_string()]).is_err());
+    assert!(Tenants::parse(&["ci:a.test".to_string(), "ci:b.test".to_string()]).is_err());
+}
+
+#[test]
+fn test_tenant_keys() {
+    let tenants = Tenants::parse(&["ci:ci.test".to_string(), "qa:qa.test,*.qa.test".to_string()]).unwrap();
+    let keys = ApiKeys::parse(
+        &[
+            "ci:ci-s3cret:read::ci".to_string(),
+            "qa:qa-s3cret:read:eu.qa.test:qa".to_string(),
+            "ops:ops-s3cret:read".to_string(),
+        ],
+        None,
+        Some("admin-s3cret"),
+        &tenants,
+    )
+    .unwrap();
+
+    let ci = keys.find("ci-s3cret").unwrap();
+    assert_eq!(ci.tenant.as_deref(), Some("ci"));
+    assert!(ci.is_restricted());
+    assert!(ci.can_see(&tagged_mail(&["x@ci.test"], &tenants)));
+    assert!(!ci.can_see(&tagged_mail(&["x@qa.test"], &tenants)));
+    assert!(!ci.can_see(&tagged_mail(&["x@other.test"], &tenants)));
+    assert!(ci.can_see(&tagged_mail(&["x@qa.test", "x@ci.test"], &tenants)));
+    // mails stored before --tenant was set have no tenant
+    assert!(!ci.can_see(&tagged_mail(&["x@ci.test"], &Tenants::default())));
+
+    // the domains restrict a tenant key further
+    let qa = keys.find("qa-s3cret").unwrap();
+    assert!(qa.can_see(&tagged_mail(&["x@eu.qa.test", "x@ci.test"], &tenants)));
+    assert!(!qa.can_see(&tagged_mail(&["x@qa.test"], &tenants)));
+
+    // keys without a tenant see every mail
+    let ops = keys.find("ops-s3cret").unwrap();
+    assert_eq!(ops.tenant, None);
+    assert!(ops.can_see(&tagged_mail(&["x@qa.test"], &tenants)));
+    assert!(keys.find("admin-s3cret").unwrap().can_see(&tagged_mail(&["x@other.test"], &tenants)));
+
+    // a tenant missing from --tenant is refused
+    assert!(ApiKeys::parse(&["ci:ci-s3cret:read::cj".to_string()], None, None, &tenants).is_err());
+}
@@ -0,0 +1,84 @@
+use crate::smtp::mail::Mail;
+use crate::webhook::*;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+// reads one request, returns its lowercased headers and its body
+async fn read_request(stream: &mut tokio::net::TcpStream) -> (HashMap<String, String>, Vec<u8>) {
+    let mut reader = BufReader::new(stream);
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let mut body = vec![0; headers["content-length"].parse().unwrap()];
+    reader.read_exact(&mut body).await.unwrap();
+    (headers, body)
+}
+
+#[test]
+fn test_filters() {
+    let mail = Mail {
+        from: ["Noreply@GitHub.com".to_string()].into(),
+        to: ["ci@ci.test".to_string()].into(),
+        ..Default::default()
+    };
+
+    let all = "http://localhost/hook".parse::<Webhook>().unwrap();
+    assert!(all.matches(&mail));
+    let both = "to:*@ci.test from:*@github.com -> http://localhost/hook"
+        .parse::<Webhook>()
+        .unwrap();
+    assert!(both.matches(&mail));
+    let other = "to:*@ci.test from:*@gitlab.com -> http://localhost/hook"
+        .parse::<Webhook>()
+        .unwrap();
+    assert!(!other.matches(&mail));
+
+    assert!("ftp://localhost/hook".parse::<Webhook>().is_err());
+    assert!("cc:*@ci.test -> http://localhost/hook".parse::<Webhook>().is_err());
+}
+
+#[tokio::test]
+async fn test_signed_delivery_with_retry() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        // fail the first attempt
+        for status in ["500 Internal Server Error", "204 No Content"] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            requests.push(read_request(&mut stream).await);
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    });
+
+    let mut webhooks = Webhooks::new(Vec::new(), Some("s3cret".to_string()), 1);
+    webhooks.backoff = Duration::from_millis(10);
+    let body = br#"{"id":1}"#.to_vec();
+    webhooks.deliver(&url, body.clone()).await.unwrap();
+
+    let requests = server.await.unwrap();
+    for (headers, received) in &requests {
+        assert_eq!(received, &body);
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers[&SIGNATURE_HEADER.to_lowercase()], sign("s3cret", &body));
+    }
+    // from `echo -n '{"id":1}' | openssl dgst -sha256 -hmac s3cret`
+    assert_eq!(
+        sign("s3cret", &body),
+        "sha256=63ddab34da5838e383545e9c90b40f74a4e3daabc5dd9a8d49a51875ad4b2418"
+    );
+}
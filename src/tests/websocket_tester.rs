@@ -0,0 +1,37 @@
+use crate::websocket::*;
+
+#[test]
+fn test_accept_key() {
+    // RFC 6455 section 1.3
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    assert_eq!(accept_key(" dGhlIHNhbXBsZSBub25jZQ== "), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[tokio::test]
+async fn test_frames() {
+    // the masked "Hello" of RFC 6455 section 5.7
+    let masked: &[u8] = &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+    assert_eq!(read_frame(&mut &masked[..]).await.unwrap(), (TEXT, b"Hello".to_vec()));
+
+    for len in [0, 125, 126, 300, 65535, 65536] {
+        let payload = vec![b'a'; len];
+        let mut frame = Vec::new();
+        write_frame(&mut frame, PING, &payload).await.unwrap();
+        assert_eq!(read_frame(&mut &frame[..]).await.unwrap(), (PING, payload));
+    }
+}
+
+#[tokio::test]
+async fn test_oversized_frames() {
+    let mut frame = Vec::new();
+    write_frame(&mut frame, TEXT, &vec![b'a'; 64 * 1024 + 1]).await.unwrap();
+    assert!(read_frame(&mut &frame[..]).await.is_err());
+
+    // refused from the header, before the payload is read
+    let mut header = vec![0x81, 0xFF];
+    header.extend_from_slice(&u64::MAX.to_be_bytes());
+    assert!(read_frame(&mut &header[..]).await.is_err());
+
+    // a frame cut short
+    assert!(read_frame(&mut &[0x81, 0x05, b'a'][..]).await.is_err());
+}
@@ -0,0 +1,184 @@
+use crate::smtp::mail::Mail;
+use crate::smtp::rules::glob_match;
+use crate::SharedError;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Header holding the HMAC-SHA256 of the body, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Mail-Sink-Signature";
+
+/// A URL to POST the stored mails to, like `to:*@ci.test -> https://ci.test/hook`.
+///
+/// The syntax is `[to:PATTERN] [from:PATTERN] -> URL`, or just `URL` for every
+/// mail. Patterns are matched like rules against every recipient or sender of
+/// the mail, case-insensitively, and all of them have to match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    pub to: Option<String>,
+    pub from: Option<String>,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Wrong webhook, expected `[to:PATTERN] [from:PATTERN] -> URL`: `{}`", s);
+        let (filters, url) = s.rsplit_once("->").unwrap_or(("", s));
+
+        let url = url.trim();
+        match url::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            _ => return Err(err()),
+        }
+
+        let mut webhook = Self {
+            url: url.to_string(),
+            to: None,
+            from: None,
+        };
+        for filter in filters.split_whitespace() {
+            match filter.split_once(':') {
+                Some((field, pattern)) if field.eq_ignore_ascii_case("to") => {
+                    webhook.to = Some(pattern.to_lowercase())
+                }
+                Some((field, pattern)) if field.eq_ignore_ascii_case("from") => {
+                    webhook.from = Some(pattern.to_lowercase())
+                }
+                _ => return Err(err()),
+            }
+        }
+        Ok(webhook)
+    }
+}
+
+impl Webhook {
+    pub fn matches(&self, mail: &Mail) -> bool {
+        let any = |pattern: &str, addresses: &HashSet<String>| {
+            addresses
+                .iter()
+                .any(|address| glob_match(pattern, &address.to_lowercase()))
+        };
+        self.to.as_deref().is_none_or(|pattern| any(pattern, &mail.to))
+            && self.from.as_deref().is_none_or(|pattern| any(pattern, &mail.from))
+    }
+}
+
+/// The configured webhooks and how they are delivered.
+pub struct Webhooks {
+    pub hooks: Vec<Webhook>,
+    /// key of the signature header, it isn't sent without one
+    pub secret: Option<String>,
+    /// attempts after the first one fails
+    pub retries: u32,
+    /// delay before the first retry, doubled after each attempt
+    pub backoff: Duration,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>, secret: Option<String>, retries: u32) -> Self {
+        Self {
+            hooks,
+            secret,
+            retries,
+            backoff: Duration::from_secs(1),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+        }
+    }
+
+    /// POSTs `body` to `url`, retrying until it answers with a 2xx status.
+    pub async fn deliver(&self, url: &str, body: Vec<u8>) -> Result<(), SharedError> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            if let Some(secret) = &self.secret {
+                request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+            }
+
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => format!("{} answered {}", url, response.status()),
+                Err(e) => format!("{}: {}", url, e),
+            };
+            if attempt >= self.retries {
+                return Err(From::from(format!("{}, giving up after {} attempts", error, attempt + 1)));
+            }
+            println!("Webhook {}, retrying in {:?}", error, backoff);
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// The `sha256=<hex>` HMAC of `body` keyed with `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+/// POSTs every mail received on `stored_mails` to the matching webhooks.
+pub async fn run(webhooks: Arc<Webhooks>, mut stored_mails: broadcast::Receiver<Arc<Mail>>) {
+    loop {
+        let mail = match stored_mails.recv().await {
+            Ok(mail) => mail,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                println!("Webhooks fell behind, {} mails were not sent", count);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        let urls = webhooks
+            .hooks
+            .iter()
+            .filter(|hook| hook.matches(&mail))
+            .map(|hook| hook.url.clone())
+            .collect::<Vec<_>>();
+        if urls.is_empty() {
+            continue;
+        }
+
+        // the same payload as GET /mails/<id>
+        let body = match crate::http::mail_to_json(&mail) {
+            Ok(json) => json.to_string().into_bytes(),
+            Err(e) => {
+                println!("Error serializing mail {} for webhooks: {:?}", mail.id, e);
+                continue;
+            }
+        };
+
+        // a slow endpoint must not hold back the others
+        for url in urls {
+            let webhooks = webhooks.clone();
+            let body = body.clone();
+            tokio::spawn(async move {
+                if let Err(e) = webhooks.deliver(&url, body).await {
+                    println!("Error sending webhook: {}", e);
+                }
+            });
+        }
+    }
+}